@@ -1,9 +1,8 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{read_dir, read_link},
-    path::PathBuf,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
     rc::Rc,
     sync::LazyLock,
     time::{Duration, Instant},
@@ -13,50 +12,181 @@ use cosmic::{
     Application, Apply, Element,
     app::{Core, Task},
     cosmic_theme::palette::WithAlpha,
-    iced::{Background, Border, Subscription, core::layout::Limits, stream::channel},
+    iced::{
+        Alignment, Background, Border, Subscription,
+        core::layout::Limits,
+        futures::SinkExt,
+        platform_specific::shell::commands::popup::{destroy_popup, get_popup},
+        stream::channel,
+        window,
+    },
     theme::{Container, Svg, Theme},
     widget::{
-        Column, Row, container::Style as CtnStyle, icon, layer_container, svg::Style as SvgStyle,
+        Column, Row, container::Style as CtnStyle, icon, layer_container, mouse_area,
+        svg::Style as SvgStyle, text, tooltip,
     },
 };
 use cosmic_time::{Timeline, anim, chain};
 
-use bimap::BiHashMap;
-use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
-use pipewire::{context::ContextRc, main_loop::MainLoopRc};
+use crate::{
+    camera::{self, Device, Kind, Process, SYS, Scope, runtime_active},
+    config::Config,
+    desktop::{self, DesktopEntry},
+    helper,
+    session::{Origin, Sessions},
+    stream::{Stream, Usage, listen},
+};
+
+const POWER_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-use crate::camera::{get_inotify, open_cameras};
+const HELPER_MISSING: &str = "Processes of other users can't be seen and may not be reported. Install the system helper to see them.";
 
 static REC_ICON: LazyLock<crate::rec_icon::Id> = LazyLock::new(crate::rec_icon::Id::unique);
 
-#[derive(Default)]
-struct Shared {
-    pub microphone: bool,
-    pub screenshare: bool,
-    pub camera: bool,
+// Ordered as displayed in the panel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Indicator {
+    Camera,
+    VirtualCamera,
+    Microphone,
+    DesktopAudio,
+    ScreenShare,
+    Biometric,
+    InputMonitoring,
+    // Index into the configured device classes
+    Class(usize),
+}
+
+impl Indicator {
+    fn icon(self, config: &Config) -> &str {
+        match self {
+            Indicator::Camera => "camera-web-symbolic",
+            Indicator::VirtualCamera => "camera-video-symbolic",
+            Indicator::Microphone => "audio-input-microphone-symbolic",
+            Indicator::DesktopAudio => "audio-speakers-symbolic",
+            Indicator::ScreenShare => "accessories-screenshot-symbolic",
+            Indicator::Biometric => "security-high-symbolic",
+            Indicator::InputMonitoring => "input-keyboard-symbolic",
+            Indicator::Class(index) => config
+                .device_classes
+                .get(index)
+                .map_or("dialog-warning-symbolic", |class| &class.icon),
+        }
+    }
+
+    fn name(self, config: &Config) -> &str {
+        match self {
+            Indicator::Camera => "Camera",
+            Indicator::VirtualCamera => "Virtual camera",
+            Indicator::Microphone => "Microphone",
+            Indicator::DesktopAudio => "Desktop audio",
+            Indicator::ScreenShare => "Screen sharing",
+            Indicator::Biometric => "Biometric authentication",
+            Indicator::InputMonitoring => "Keyboard monitoring",
+            Indicator::Class(index) => config
+                .device_classes
+                .get(index)
+                .map_or("Device", |class| &class.label),
+        }
+    }
+
+    // Holders of its devices that don't count as usage
+    fn ignored(self, config: &Config) -> &[String] {
+        match self {
+            Indicator::Microphone => &config.sound_servers,
+            Indicator::InputMonitoring => &config.input_servers,
+            Indicator::DesktopAudio | Indicator::ScreenShare => &[],
+            _ => &config.probe_processes,
+        }
+    }
+
+    // Where a device tracked through /proc is reported
+    fn of(device: &Device) -> Self {
+        match device.kind {
+            Kind::Camera => Indicator::Camera,
+            Kind::VirtualCamera => Indicator::VirtualCamera,
+            Kind::Biometric => Indicator::Biometric,
+            Kind::Microphone => Indicator::Microphone,
+            Kind::Input => Indicator::InputMonitoring,
+            Kind::Class(index) => Indicator::Class(index),
+        }
+    }
+}
+
+// A row of the popup
+struct Capture {
+    indicator: Indicator,
+    app: String,
+    icon: icon::Handle,
+    device: Option<String>,
+    // Unknown for holders that were there before the applet started
+    since: Option<Instant>,
+}
+
+fn app_icon(entry: Option<&DesktopEntry>, fallback: Option<&str>) -> icon::Handle {
+    match entry.and_then(|entry| entry.icon.as_deref()).or(fallback) {
+        Some(path) if path.starts_with('/') => icon::from_path(PathBuf::from(path)),
+        Some(name) => icon::from_name(name).into(),
+        None => icon::from_name("application-x-executable-symbolic").into(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes}:{secs:02}")
+    }
 }
 
 #[derive(Default)]
 pub struct PrivacyIndicator {
     core: Core,
     timeline: Timeline,
-    shared: Shared,
-    microphones: HashSet<u32>,
-    screenshares: HashSet<u32>,
-    cameras: HashMap<PathBuf, (i32, i32)>,
+    shared: BTreeMap<Indicator, Usage>,
+    // Active indicators are kept visible until the minimum display time runs out
+    holds: HashMap<Indicator, Instant>,
+    config: Config,
+    microphones: HashMap<u32, Stream>,
+    desktop_audio: HashMap<u32, Stream>,
+    screenshares: HashMap<u32, Stream>,
+    camera_streams: HashMap<u32, Stream>,
+    virtual_camera_streams: HashMap<u32, Stream>,
+    biometric_streams: HashMap<u32, Stream>,
+    devices: Sessions,
+    // Other users' processes can't be seen and the system helper couldn't be reached
+    helper_missing: bool,
+    popup: Option<window::Id>,
+    // When each PipeWire stream showed up
+    stream_since: HashMap<u32, Instant>,
+    // App id -> its .desktop file, looked up once
+    apps: HashMap<String, Option<DesktopEntry>>,
+    // When the pending Tick fires, a new one is only needed for an earlier refresh
+    next_tick: Option<Instant>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     Tick,
     RecTick(Instant),
-    ScreenShareAdd(u32),
-    MicrophoneAdd(u32),
+    Config(Config),
+    ScreenShareAdd(u32, Stream),
+    MicrophoneAdd(u32, Stream),
+    DesktopAudioAdd(u32, Stream),
+    CameraStreamAdd(u32, Stream),
+    VirtualCameraStreamAdd(u32, Stream),
+    BiometricStreamAdd(u32, Stream),
     PipeWireNodeRemove(u32),
-    CameraOpen(PathBuf),
-    CameraClose(PathBuf),
-    CameraPrevious(HashMap<PathBuf, (i32, i32)>),
-    CameraReset(PathBuf),
+    PipeWireReset,
+    DeviceHolders(Device, Vec<Process>),
+    DevicePrevious(Scope, HashMap<Device, Vec<Process>>),
+    DeviceReconcile(Scope, HashMap<Device, Vec<Process>>),
+    DeviceReset(Device),
+    HelperAvailable(bool),
+    TogglePopup,
+    PopupClosed(window::Id),
 }
 
 impl Application for PrivacyIndicator {
@@ -83,6 +213,7 @@ impl Application for PrivacyIndicator {
         let app = PrivacyIndicator {
             core,
             timeline,
+            config: Config::load(Self::APP_ID),
             ..Default::default()
         };
 
@@ -95,40 +226,59 @@ impl Application for PrivacyIndicator {
         let pad = self.core.applet.suggested_padding(true);
 
         let mut shared: Vec<Element<Self::Message>> = vec![];
-        let Shared {
-            microphone,
-            screenshare,
-            camera,
-        } = self.shared;
-
-        if screenshare || microphone || camera {
-            shared.push(anim![REC_ICON, &self.timeline, size.0].into());
-        } else {
-            return self
-                .core
-                .applet
-                .autosize_window("")
-                .limits(Limits::NONE)
-                .into();
+
+        // Devices held by idle streams are shown without the recording dot
+        match self.shared.values().max() {
+            Some(Usage::Active) => {
+                shared.push(anim![REC_ICON, &self.timeline, size.0].into());
+            }
+            Some(Usage::Held) => {}
+            // The warning stays visible while nothing is shared
+            _ if self.helper_missing => {}
+            _ => {
+                return self
+                    .core
+                    .applet
+                    .autosize_window("")
+                    .limits(Limits::NONE)
+                    .into();
+            }
         }
 
         let icon_style = Rc::new(|theme: &Theme| SvgStyle {
             color: Some(theme.cosmic().button_color().into()),
         });
-        let indicator = |name: &str| {
-            icon(icon::from_name(name).into())
-                .class(Svg::Custom(icon_style.clone()))
-                .size(size.0)
-        };
-
-        if camera {
-            shared.push(indicator("camera-web-symbolic").into());
-        }
-        if microphone {
-            shared.push(indicator("audio-input-microphone-symbolic").into());
+        let held_style = Rc::new(|theme: &Theme| SvgStyle {
+            color: Some(theme.cosmic().button_color().with_alpha(0.5).into()),
+        });
+        for (indicator, &usage) in &self.shared {
+            let style = if usage == Usage::Held {
+                held_style.clone()
+            } else {
+                icon_style.clone()
+            };
+            let icon = icon(icon::from_name(indicator.icon(&self.config)).into())
+                .class(Svg::Custom(style))
+                .size(size.0);
+            let holders = self
+                .devices
+                .holders()
+                .filter(|(device, _)| Indicator::of(device) == *indicator)
+                .map(|(device, holder)| format!("{}: {}", device.label(), holder.process.comm))
+                .collect::<Vec<_>>();
+            if holders.is_empty() {
+                shared.push(icon.into());
+            } else {
+                let label = text(holders.join("\n"));
+                shared.push(tooltip(icon, label, tooltip::Position::Bottom).into());
+            }
         }
-        if screenshare {
-            shared.push(indicator("accessories-screenshot-symbolic").into());
+        if self.helper_missing {
+            let icon = icon(icon::from_name("dialog-warning-symbolic").into())
+                .class(Svg::Custom(icon_style.clone()))
+                .size(size.0);
+            let label = text(HELPER_MISSING);
+            shared.push(tooltip(icon, label, tooltip::Position::Bottom).into());
         }
 
         let container_style = |theme: &Theme| {
@@ -156,6 +306,8 @@ impl Application for PrivacyIndicator {
         .padding(pad.0.min(pad.1))
         .class(Container::Custom(Box::new(container_style)));
 
+        let container = mouse_area(container).on_press(Message::TogglePopup);
+
         self.core
             .applet
             .autosize_window(container)
@@ -163,199 +315,434 @@ impl Application for PrivacyIndicator {
             .into()
     }
 
+    fn view_window(&self, _id: window::Id) -> Element<'_, Self::Message> {
+        let now = Instant::now();
+        let mut list = Column::new().spacing(12).padding(12);
+        let captures = self.captures();
+        if captures.is_empty() {
+            list = list.push(text::body("Nothing is being captured"));
+        }
+        for capture in captures {
+            let duration = capture
+                .since
+                .map(|since| format_duration(now.saturating_duration_since(since)));
+            let details = [
+                Some(capture.indicator.name(&self.config)),
+                capture.device.as_deref(),
+                duration.as_deref(),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" · ");
+            let row = Row::new()
+                .spacing(12)
+                .align_y(Alignment::Center)
+                .push(icon(capture.icon).size(32))
+                .push(
+                    Column::new()
+                        .push(text::body(capture.app))
+                        .push(text::caption(details)),
+                );
+            list = list.push(row);
+        }
+        if self.helper_missing {
+            list = list.push(text::caption(HELPER_MISSING));
+        }
+        self.core.applet.popup_container(list).into()
+    }
+
+    fn on_close_requested(&self, id: window::Id) -> Option<Self::Message> {
+        Some(Message::PopupClosed(id))
+    }
+
     fn update(&mut self, message: Self::Message) -> Task<Self::Message> {
+        if let Message::ScreenShareAdd(id, _)
+        | Message::MicrophoneAdd(id, _)
+        | Message::DesktopAudioAdd(id, _)
+        | Message::CameraStreamAdd(id, _)
+        | Message::VirtualCameraStreamAdd(id, _)
+        | Message::BiometricStreamAdd(id, _) = &message
+        {
+            self.stream_since.entry(*id).or_insert_with(Instant::now);
+        }
         match message {
-            Message::Tick => {
-                self.shared = Shared {
-                    microphone: !self.microphones.is_empty(),
-                    screenshare: !self.screenshares.is_empty(),
-                    camera: self
-                        .cameras
-                        .values()
-                        .fold(0, |acc, (shares, min)| acc + shares - min)
-                        > 0,
+            Message::TogglePopup => {
+                return if let Some(id) = self.popup.take() {
+                    destroy_popup(id)
+                } else {
+                    let id = window::Id::unique();
+                    self.popup = Some(id);
+                    let mut settings = self.core.applet.get_popup_settings(
+                        self.core.main_window_id().unwrap(),
+                        id,
+                        None,
+                        None,
+                        None,
+                    );
+                    settings.positioner.size_limits = Limits::NONE
+                        .min_width(300.0)
+                        .max_width(400.0)
+                        .max_height(800.0);
+                    get_popup(settings)
                 };
             }
-            Message::CameraPrevious(cameras) => self.cameras = cameras,
-            Message::CameraOpen(path) => {
-                self.cameras
-                    .entry(path)
-                    .and_modify(|v| v.0 += 1)
-                    .or_insert((1, 0));
+            Message::PopupClosed(id) => {
+                if self.popup == Some(id) {
+                    self.popup = None;
+                }
+                return Task::none();
+            }
+            Message::Tick => {}
+            Message::Config(config) => self.config = config,
+            Message::DevicePrevious(scope, devices) => self.devices.start(scope, devices),
+            Message::DeviceHolders(device, processes) => {
+                self.devices.update(device, processes, Instant::now());
             }
-            Message::CameraClose(path) => {
-                self.cameras
-                    .entry(path)
-                    .and_modify(|v| {
-                        v.0 -= 1;
-                        v.1 = v.1.min(v.0);
-                    })
-                    .or_insert((0, 0));
+            Message::DeviceReconcile(scope, devices) => self.devices.reconcile(scope, devices),
+            Message::DeviceReset(device) => self.devices.reset(&device),
+            Message::HelperAvailable(available) => self.helper_missing = !available,
+            Message::ScreenShareAdd(id, stream) => {
+                self.screenshares.insert(id, stream);
             }
-            Message::CameraReset(path) => {
-                self.cameras.remove(&path);
+            Message::MicrophoneAdd(id, stream) => {
+                self.microphones.insert(id, stream);
             }
-            Message::ScreenShareAdd(id) => {
-                self.screenshares.insert(id);
+            Message::DesktopAudioAdd(id, stream) => {
+                self.desktop_audio.insert(id, stream);
             }
-            Message::MicrophoneAdd(id) => {
-                self.microphones.insert(id);
+            Message::CameraStreamAdd(id, stream) => {
+                self.camera_streams.insert(id, stream);
+            }
+            Message::VirtualCameraStreamAdd(id, stream) => {
+                self.virtual_camera_streams.insert(id, stream);
+            }
+            Message::BiometricStreamAdd(id, stream) => {
+                self.biometric_streams.insert(id, stream);
             }
             Message::PipeWireNodeRemove(id) => {
+                self.stream_since.remove(&id);
                 self.screenshares.remove(&id);
                 self.microphones.remove(&id);
+                self.desktop_audio.remove(&id);
+                self.camera_streams.remove(&id);
+                self.virtual_camera_streams.remove(&id);
+                self.biometric_streams.remove(&id);
+            }
+            Message::PipeWireReset => {
+                self.stream_since.clear();
+                self.screenshares.clear();
+                self.microphones.clear();
+                self.desktop_audio.clear();
+                self.camera_streams.clear();
+                self.virtual_camera_streams.clear();
+                self.biometric_streams.clear();
+            }
+            Message::RecTick(now) => {
+                self.timeline.now(now);
+                return Task::none();
             }
-            Message::RecTick(now) => self.timeline.now(now),
         }
-        Task::none()
+        self.refresh()
     }
 
     fn subscription(&self) -> Subscription<Self::Message> {
-        let pw_shares = Subscription::run(|| {
-            channel(100, |output| async {
-                std::thread::spawn(move || {
-                    pipewire::init();
-                    let main_loop =
-                        MainLoopRc::new(None).expect("Failed to create PipeWire main loop");
-                    let context = ContextRc::new(&main_loop, None)
-                        .expect("Failed to create PipeWire context");
-                    let core = context
-                        .connect(None)
-                        .expect("Failed to connect to PipeWire");
-                    let registry = core
-                        .get_registry()
-                        .expect("Failed to get PipeWire registry");
-                    let output_remove = output.clone();
-                    let _listener = registry
-                        .add_listener_local()
-                        .global(move |global| {
-                            if global.type_.to_str() == "PipeWire:Interface:Node" {
-                                global.props.map(|props| {
-                                    props
-                                        .get("media.class")
-                                        .map(|media_class| match media_class {
-                                            "Stream/Input/Video" => {
-                                                // Screen captures/recordings in wayland are usually done through pipewire
-                                                let mut output = output.clone();
-                                                while output
-                                                    .try_send(Message::ScreenShareAdd(global.id))
-                                                    .is_err()
-                                                {
-                                                    eprintln!(
-                                                        "Failed to send ScreenCast share event"
-                                                    );
-                                                }
-                                            }
-                                            "Stream/Input/Audio" => {
-                                                // Microphones are
-                                                let mut output = output.clone();
-                                                while output
-                                                    .try_send(Message::MicrophoneAdd(global.id))
-                                                    .is_err()
-                                                {
-                                                    eprintln!(
-                                                        "Failed to send Microphone share event"
-                                                    );
-                                                }
-                                            }
-                                            _ => (),
-                                        })
-                                });
-                            }
-                        })
-                        .global_remove(move |id| {
-                            let mut output = output_remove.clone();
-                            while output.try_send(Message::PipeWireNodeRemove(id)).is_err() {
-                                eprintln!("Failed to send unshare event");
-                            }
-                        })
-                        .register();
-                    main_loop.run();
-                });
-            })
-        });
+        // Restarted whenever the IR camera overrides change
+        let ir_cameras = self.config.ir_cameras.clone();
+        let pw_shares = Subscription::run_with_id(
+            ir_cameras.clone(),
+            channel(100, {
+                let ir_cameras = ir_cameras.clone();
+                move |output| async move {
+                    std::thread::spawn(move || listen(output, ir_cameras));
+                }
+            }),
+        );
 
-        let camera_shares = Subscription::run(|| {
-            channel(100, |mut output| async {
-                std::thread::spawn(move || {
-                    let open_cameras = open_cameras();
-                    while output
-                        .try_send(Message::CameraPrevious(open_cameras.clone()))
-                        .is_err()
-                    {
-                        eprintln!("Failed to send previously open camera event");
+        // Restarted with the new classes whenever they change
+        let classes = self.config.device_classes.clone();
+        let device_shares = Subscription::run_with_id(
+            (classes.clone(), ir_cameras.clone()),
+            channel(100, move |mut output| async move {
+                // The system helper can still see every process, custom classes are always
+                // watched here since they're configured per user
+                let mut scope = Scope::All;
+                if !camera::sandboxed() && !camera::privileged() {
+                    if !classes.is_empty() {
+                        let output = output.clone();
+                        let classes = classes.clone();
+                        std::thread::spawn(move || {
+                            camera::listen(output, classes, Vec::new(), Scope::Classes)
+                        });
                     }
-                    let (mut inotify, mut wd_path) = get_inotify();
-                    let mut event_buffer = [0; 1024];
-
-                    loop {
-                        for event in inotify
-                            .read_events_blocking(&mut event_buffer)
-                            .expect("Failed to read events")
-                        {
-                            match event.mask {
-                                EventMask::CREATE | EventMask::ATTRIB | EventMask::DELETE_SELF => {
-                                    if event.mask == EventMask::DELETE_SELF
-                                        || event
-                                            .name
-                                            .unwrap_or_default()
-                                            .to_string_lossy()
-                                            .starts_with("video")
-                                    {
-                                        let old_wd_paths = wd_path;
-                                        (inotify, wd_path) = get_inotify();
-                                        let old_paths =
-                                            old_wd_paths.left_values().collect::<HashSet<_>>();
-                                        let new_paths =
-                                            wd_path.left_values().collect::<HashSet<_>>();
-                                        for &path in old_paths.difference(&new_paths) {
-                                            while output
-                                                .try_send(Message::CameraReset(path.clone()))
-                                                .is_err()
-                                            {
-                                                eprintln!("Failed to send camera reset event");
-                                            }
-                                        }
-                                    }
-                                }
-                                EventMask::OPEN => {
-                                    wd_path.get_by_right(&event.wd).inspect(|&path| {
-                                        println!("open {path:?}");
-                                        while output
-                                            .try_send(Message::CameraOpen(path.clone()))
-                                            .is_err()
-                                        {
-                                            eprintln!("Failed to send camera open event");
-                                        }
-                                    });
-                                }
-                                EventMask::CLOSE_WRITE | EventMask::CLOSE_NOWRITE => {
-                                    wd_path.get_by_right(&event.wd).inspect(|&path| {
-                                        println!("close {path:?}");
-                                        while output
-                                            .try_send(Message::CameraClose(path.clone()))
-                                            .is_err()
-                                        {
-                                            eprintln!("Failed to send camera close event");
-                                        }
-                                    });
-                                }
-                                _ => continue,
-                            };
-                        }
-                    }
-                });
-            })
-        });
+                    let _ = output.send(Message::HelperAvailable(true)).await;
+                    let Err(err) = helper::subscribe(&mut output).await else {
+                        return;
+                    };
+                    eprintln!("System helper unavailable, falling back to /proc: {err}");
+                    let _ = output.send(Message::HelperAvailable(false)).await;
+                    scope = Scope::Builtin;
+                }
+                std::thread::spawn(move || camera::listen(output, classes, ir_cameras, scope));
+            }),
+        );
 
         // Weirdly enough, self.timeline.as_subscription() is too resource heavy, since it follows the compositors refresh rate
         let timeline = cosmic::iced::time::every(Duration::from_millis(20)).map(Message::RecTick); // 50Hz
-        let tick = cosmic::iced::time::every(Duration::from_millis(2000)).map(|_| Message::Tick);
 
-        Subscription::batch([pw_shares, camera_shares, timeline, tick])
+        let config = self
+            .core
+            .watch_config::<Config>(Self::APP_ID)
+            .map(|update| {
+                for err in update.errors {
+                    eprintln!("Failed to load config: {err}");
+                }
+                Message::Config(update.config)
+            });
+
+        Subscription::batch([pw_shares, device_shares, timeline, config])
     }
 
     fn style(&self) -> Option<cosmic::iced_runtime::Appearance> {
         Some(cosmic::applet::style())
     }
 }
+
+impl PrivacyIndicator {
+    fn refresh(&mut self) -> Task<Message> {
+        let now = Instant::now();
+        if self.next_tick.is_some_and(|tick| tick <= now) {
+            self.next_tick = None;
+        }
+        let mut wake_up: Option<Instant> = None;
+        let mut wake_up_at = |until: Instant| {
+            wake_up = Some(wake_up.map_or(until, |wake_up| wake_up.min(until)));
+        };
+
+        let min_hold = Duration::from_millis(self.config.camera_min_hold_ms);
+        self.resolve_apps();
+        let config = &self.config;
+        let mut device_active = |indicator: Indicator| {
+            let (active, pending) = self.devices.active(
+                |device| Indicator::of(device) == indicator,
+                indicator.ignored(config),
+                min_hold,
+                now,
+            );
+            if let Some(until) = pending {
+                wake_up_at(until);
+            }
+            active
+        };
+        // A loopback writer fed by a real camera holds that camera too, which is reported as usual
+        let camera_open = device_active(Indicator::Camera);
+        let virtual_camera = device_active(Indicator::VirtualCamera);
+        let biometric = device_active(Indicator::Biometric);
+        let alsa = device_active(Indicator::Microphone);
+        let input = device_active(Indicator::InputMonitoring);
+        let classes = (0..config.device_classes.len())
+            .map(|index| {
+                let active = device_active(Indicator::Class(index));
+                let usage = if active { Usage::Active } else { Usage::None };
+                (Indicator::Class(index), usage)
+            })
+            .collect::<Vec<_>>();
+
+        // Apps often open a camera long before streaming from it, USB cameras stay
+        // runtime suspended until then. Power changes have no events, so they're polled.
+        let sys = Path::new(SYS);
+        let camera_streaming = self
+            .devices
+            .sessions(
+                |device| Indicator::of(device) == Indicator::Camera,
+                Indicator::Camera.ignored(config),
+                min_hold,
+                now,
+            )
+            .any(|(device, _)| runtime_active(sys, &device.path) != Some(false));
+        // Streaming may stop while the camera stays open, just as well as start
+        if camera_open {
+            wake_up_at(now + POWER_POLL_INTERVAL);
+        }
+        let camera = if camera_streaming {
+            Usage::Active
+        } else if camera_open {
+            Usage::Held
+        } else {
+            Usage::None
+        };
+
+        let usage = |streams: &HashMap<u32, Stream>| {
+            Usage::of(
+                streams
+                    .values()
+                    .filter(|stream| self.config.reports(stream)),
+            )
+        };
+        let current = [
+            (Indicator::Camera, camera.max(usage(&self.camera_streams))),
+            (
+                Indicator::VirtualCamera,
+                if !self.config.show_virtual_cameras {
+                    Usage::None
+                } else if virtual_camera {
+                    Usage::Active
+                } else {
+                    usage(&self.virtual_camera_streams)
+                },
+            ),
+            (
+                Indicator::Microphone,
+                if alsa {
+                    Usage::Active
+                } else {
+                    usage(&self.microphones)
+                },
+            ),
+            (Indicator::DesktopAudio, usage(&self.desktop_audio)),
+            (Indicator::ScreenShare, usage(&self.screenshares)),
+            (
+                Indicator::Biometric,
+                if biometric {
+                    Usage::Active
+                } else {
+                    usage(&self.biometric_streams)
+                },
+            ),
+            (
+                Indicator::InputMonitoring,
+                if input { Usage::Active } else { Usage::None },
+            ),
+        ];
+
+        let min_display = Duration::from_millis(self.config.min_display_ms);
+        self.shared.clear();
+        for (indicator, usage) in current.into_iter().chain(classes) {
+            let usage = if usage == Usage::Active {
+                self.holds.insert(indicator, now + min_display);
+                usage
+            } else if let Some(&until) = self.holds.get(&indicator)
+                && until > now
+            {
+                wake_up_at(until);
+                Usage::Active
+            } else {
+                self.holds.remove(&indicator);
+                usage
+            };
+            if usage != Usage::None {
+                self.shared.insert(indicator, usage);
+            }
+        }
+
+        match wake_up {
+            Some(until) if self.next_tick.is_none_or(|tick| until < tick) => {
+                self.next_tick = Some(until);
+                cosmic::task::future(async move {
+                    tokio::time::sleep_until(until.into()).await;
+                    Message::Tick
+                })
+            }
+            _ => Task::none(),
+        }
+    }
+
+    // Reported PipeWire streams, with where they're shown
+    fn streams(&self) -> impl Iterator<Item = (Indicator, u32, &Stream)> {
+        let virtual_cameras = self
+            .config
+            .show_virtual_cameras
+            .then_some(&self.virtual_camera_streams);
+        [
+            (Indicator::Camera, Some(&self.camera_streams)),
+            (Indicator::VirtualCamera, virtual_cameras),
+            (Indicator::Microphone, Some(&self.microphones)),
+            (Indicator::DesktopAudio, Some(&self.desktop_audio)),
+            (Indicator::ScreenShare, Some(&self.screenshares)),
+            (Indicator::Biometric, Some(&self.biometric_streams)),
+        ]
+        .into_iter()
+        .flat_map(|(indicator, streams)| {
+            streams
+                .into_iter()
+                .flatten()
+                .map(move |(&id, stream)| (indicator, id, stream))
+        })
+        .filter(|(_, _, stream)| self.config.reports(stream))
+    }
+
+    // Looks up the .desktop files of apps that weren't seen yet, the popup can't do IO
+    fn resolve_apps(&mut self) {
+        let ids = self
+            .streams()
+            .filter_map(|(_, _, stream)| stream_app_id(stream))
+            .chain(
+                self.devices
+                    .holders()
+                    .map(|(_, holder)| process_app_id(&holder.process)),
+            )
+            .filter(|id| !self.apps.contains_key(*id))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        for id in ids {
+            let entry = desktop::lookup(&id);
+            self.apps.insert(id, entry);
+        }
+    }
+
+    fn captures(&self) -> Vec<Capture> {
+        let entry = |id: Option<&str>| id.and_then(|id| self.apps.get(id)?.as_ref());
+        let streams = self.streams().map(|(indicator, id, stream)| {
+            let entry = entry(stream_app_id(stream));
+            Capture {
+                indicator,
+                app: entry
+                    .and_then(|entry| entry.name.clone())
+                    .unwrap_or_else(|| stream.app.display_name().to_owned()),
+                icon: app_icon(entry, stream.app.icon_name.as_deref()),
+                device: stream.device.clone(),
+                since: self.stream_since.get(&id).copied(),
+            }
+        });
+        let holders = self.devices.holders().filter_map(|(device, holder)| {
+            let indicator = Indicator::of(device);
+            if indicator
+                .ignored(&self.config)
+                .contains(&holder.process.comm)
+                || (indicator == Indicator::VirtualCamera && !self.config.show_virtual_cameras)
+            {
+                return None;
+            }
+            let entry = entry(Some(process_app_id(&holder.process)));
+            Some(Capture {
+                indicator,
+                app: entry
+                    .and_then(|entry| entry.name.clone())
+                    .unwrap_or_else(|| holder.process.comm.clone()),
+                icon: app_icon(entry, None),
+                device: Some(device.label()),
+                since: match holder.origin {
+                    Origin::Opened(since) => Some(since),
+                    Origin::Preexisting | Origin::Discovered => None,
+                },
+            })
+        });
+        let mut captures = streams.chain(holders).collect::<Vec<_>>();
+        captures.sort_by(|a, b| (a.indicator, &a.app).cmp(&(b.indicator, &b.app)));
+        captures
+    }
+}
+
+// Sandboxed apps are known by their Flatpak id, others by their binary, which often matches
+fn stream_app_id(stream: &Stream) -> Option<&str> {
+    stream
+        .app
+        .flatpak_id
+        .as_deref()
+        .or(stream.app.binary.as_deref())
+}
+
+// Launched apps run in a scope named after their app id
+fn process_app_id(process: &Process) -> &str {
+    process.app_id.as_deref().unwrap_or(&process.comm)
+}
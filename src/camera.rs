@@ -1,71 +1,1279 @@
 use std::{
     collections::HashMap,
-    fs::{read_dir, read_link},
-    path::PathBuf,
+    ffi::{CString, OsStr},
+    fs::{File, read_dir, read_link, read_to_string},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{MetadataExt, OpenOptionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use bimap::BiHashMap;
-use inotify::{Inotify, WatchDescriptor, WatchMask};
+use cosmic::iced::futures::channel::mpsc::Sender;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use serde::{Deserialize, Serialize};
 
-pub fn open_cameras() -> HashMap<PathBuf, (i32, i32)> {
-    if std::path::Path::new("/.flatpak-info").exists() {
+use crate::{
+    applet::Message,
+    config::DeviceClass,
+    fanotify::{Access, Fanotify},
+    hotplug::{Action, DeviceInfo, HotplugSource, UdevMonitor},
+    media::query_media,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Process {
+    pub pid: u32,
+    pub comm: String,
+    pub exe: Option<PathBuf>,
+    pub cmdline: Vec<String>,
+    pub app_id: Option<String>,
+    // The system helper only shows a process to the user running it
+    pub uid: Option<u32>,
+}
+
+impl Process {
+    // Stands in for holders that /proc doesn't show, e.g. root processes when unprivileged
+    pub fn unknown() -> Self {
+        Process {
+            pid: 0,
+            comm: "Unknown process".to_owned(),
+            exe: None,
+            cmdline: Vec::new(),
+            app_id: None,
+            uid: None,
+        }
+    }
+
+    fn read(dir: &Path, pid: u32) -> Self {
+        let comm = read_to_string(dir.join("comm"))
+            .map(|comm| comm.trim_end().to_owned())
+            .unwrap_or_default();
+        let cmdline = std::fs::read(dir.join("cmdline"))
+            .map(|cmdline| {
+                cmdline
+                    .split(|&b| b == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let app_id = read_to_string(dir.join("cgroup"))
+            .ok()
+            .and_then(|cgroup| cgroup.lines().find_map(app_id_from_cgroup));
+        Process {
+            pid,
+            comm,
+            exe: read_link(dir.join("exe")).ok(),
+            cmdline,
+            app_id,
+            uid: dir.metadata().ok().map(|metadata| metadata.uid()),
+        }
+    }
+}
+
+// Desktop launchers put apps in a unit named after their app id, following
+// `app[-<launcher>]-<app id>-<random>.scope` or `app[-<launcher>]-<app id>[@<random>].service`
+fn app_id_from_cgroup(line: &str) -> Option<String> {
+    let unit = line.rsplit('/').next()?.strip_prefix("app-")?;
+    let name = if let Some(scope) = unit.strip_suffix(".scope") {
+        scope.rsplit_once('-')?.0
+    } else {
+        let service = unit.strip_suffix(".service")?;
+        service.split_once('@').map_or(service, |(name, _)| name)
+    };
+    // Dashes inside the app id are escaped, so any remaining one separates the launcher
+    let id = name.split_once('-').map_or(name, |(_, id)| id);
+    Some(id.replace("\\x2d", "-"))
+}
+
+pub const PROC: &str = "/proc";
+
+// Flatpak only shows the processes of the sandbox in /proc
+pub fn sandboxed() -> bool {
+    Path::new("/.flatpak-info").exists()
+}
+
+// Without root the fds of other users' processes can't be read, with hidepid they aren't even listed
+pub fn privileged() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+// Lists a process once for every handle it has open on a node.
+// `proc` is the procfs mount to scan, so that it can be pointed at a fake tree.
+fn scan_proc(proc: &Path, filter: impl Fn(&Path) -> bool) -> HashMap<PathBuf, Vec<Process>> {
+    if sandboxed() {
         return HashMap::new();
     }
 
-    read_dir("/proc")
-        .and_then(|paths| {
-            let res = paths
-                .flatten()
-                .filter(|pid| {
-                    pid.file_name()
-                        .to_string_lossy()
-                        .bytes()
-                        .all(|b| b.is_ascii_digit())
-                })
-                .filter_map(|pid| {
-                    read_dir(pid.path().join("fd"))
-                        .ok()
-                        .map(|fds| fds.flatten().map(|p| p.path()))
-                })
-                .flatten()
-                .filter_map(|fd| {
-                    let Ok(path) = read_link(fd) else {
-                        return None;
-                    };
-                    if path.to_string_lossy().starts_with("/dev/video") {
-                        Some(PathBuf::from(path))
-                    } else {
-                        None
-                    }
-                })
-                .fold(HashMap::<PathBuf, (i32, i32)>::new(), |mut hm, p| {
-                    hm.entry(p).and_modify(|fds| fds.0 += 1).or_insert((1, 0));
-                    hm
-                });
-            Ok(res)
+    let Ok(pids) = read_dir(proc) else {
+        return HashMap::new();
+    };
+    let mut holders = HashMap::<PathBuf, Vec<Process>>::new();
+    for entry in pids.flatten() {
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        // Our own capability queries aren't device usage
+        if pid == std::process::id() {
+            continue;
+        }
+        let Ok(fds) = read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let mut process = None;
+        for fd in fds.flatten() {
+            let Ok(path) = read_link(fd.path()) else {
+                continue;
+            };
+            if !filter(&path) {
+                continue;
+            }
+            let process = process.get_or_insert_with(|| Process::read(&entry.path(), pid));
+            holders.entry(path).or_default().push(process.clone());
+        }
+    }
+    holders
+}
+
+pub const SYS: &str = "/sys";
+
+// Whether the USB device behind a camera node is powered up, which uvcvideo only does while
+// it's in use. None when runtime PM can't tell, e.g. for non-USB cameras or with autosuspend
+// disabled. `sys` is the sysfs mount, so that it can be pointed at a fake tree.
+pub fn runtime_active(sys: &Path, node: &Path) -> Option<bool> {
+    let name = node.file_name()?;
+    let interface = sys
+        .join("class/video4linux")
+        .join(name)
+        .join("device")
+        .canonicalize()
+        .ok()?;
+    let usb = interface
+        .ancestors()
+        .take_while(|dir| dir.starts_with(sys))
+        .find(|dir| dir.join("idVendor").exists())?;
+    let power = usb.join("power");
+    // Autosuspend is off, so the device stays powered whether it streams or not
+    if read_to_string(power.join("control")).ok()?.trim() == "on" {
+        return None;
+    }
+    match read_to_string(power.join("runtime_status")).ok()?.trim() {
+        "active" | "resuming" => Some(true),
+        "suspended" | "suspending" => Some(false),
+        _ => None,
+    }
+}
+
+// _IOR('V', 0, struct v4l2_capability)
+const VIDIOC_QUERYCAP: u64 = 0x8068_5600;
+const V4L2_CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+const V4L2_CAP_VIDEO_CAPTURE_MPLANE: u32 = 0x0000_1000;
+const V4L2_CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+// _IOWR('V', 2, struct v4l2_fmtdesc)
+const VIDIOC_ENUM_FMT: u64 = 0xC040_5602;
+const V4L2_BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+const V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE: u32 = 9;
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+// Luminance-only formats, the only ones IR sensors produce
+const IR_FORMATS: [u32; 6] = [
+    fourcc(b"GREY"),
+    fourcc(b"Y10 "),
+    fourcc(b"Y12 "),
+    fourcc(b"Y16 "),
+    fourcc(b"Y8I "),
+    fourcc(b"Y12I"),
+];
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct V4l2FmtDesc {
+    index: u32,
+    type_: u32,
+    flags: u32,
+    description: [u8; 32],
+    pixelformat: u32,
+    mbus_code: u32,
+    reserved: [u32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub driver: String,
+    pub card: String,
+    pub bus_info: String,
+    pub capture: bool,
+    // Capture pixel formats, as fourcc codes
+    pub formats: Vec<u32>,
+}
+
+impl Capabilities {
+    // v4l2loopback devices (OBS Virtual Camera and the like) are fed by another process,
+    // with exclusive_caps they only advertise capture once that writer is running
+    pub fn is_loopback(&self) -> bool {
+        self.driver == "v4l2 loopback"
+    }
+
+    // Face unlock sensors (Howdy, Windows Hello) sit next to the webcam as a greyscale node
+    pub fn is_infrared(&self) -> bool {
+        !self.formats.is_empty()
+            && self
+                .formats
+                .iter()
+                .all(|format| IR_FORMATS.contains(format))
+    }
+
+    // None for nodes that can't capture, like metadata nodes and encoders
+    pub fn kind(&self) -> Option<Kind> {
+        if self.is_loopback() {
+            Some(Kind::VirtualCamera)
+        } else if !self.capture {
+            None
+        } else if self.is_infrared() {
+            Some(Kind::Biometric)
+        } else {
+            Some(Kind::Camera)
+        }
+    }
+}
+
+// IR sensors whose formats don't give them away are configured by node or name
+pub fn is_ir_camera(ir_cameras: &[String], node: &Path, name: Option<&str>) -> bool {
+    ir_cameras
+        .iter()
+        .any(|camera| name == Some(camera) || node == Path::new(camera))
+}
+
+pub fn query_capabilities(node: &Path) -> Option<Capabilities> {
+    let file = File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(node)
+        .ok()?;
+    let mut cap = V4l2Capability::default();
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), VIDIOC_QUERYCAP as _, &raw mut cap) };
+    if res < 0 {
+        return None;
+    }
+    let string = |bytes: &[u8]| {
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+    // Capabilities describe the whole physical device, device_caps only this node
+    let caps = if cap.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+        cap.device_caps
+    } else {
+        cap.capabilities
+    };
+    let type_ = if caps & V4L2_CAP_VIDEO_CAPTURE_MPLANE != 0 {
+        V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE
+    } else {
+        V4L2_BUF_TYPE_VIDEO_CAPTURE
+    };
+    let mut formats = vec![];
+    // Enumeration ends with EINVAL on the first index past the last format
+    for index in 0.. {
+        let mut desc = V4l2FmtDesc {
+            index,
+            type_,
+            ..Default::default()
+        };
+        let res = unsafe { libc::ioctl(file.as_raw_fd(), VIDIOC_ENUM_FMT as _, &raw mut desc) };
+        if res < 0 {
+            break;
+        }
+        formats.push(desc.pixelformat);
+    }
+    Some(Capabilities {
+        driver: string(&cap.driver),
+        card: string(&cap.card),
+        bus_info: string(&cap.bus_info),
+        capture: caps & (V4L2_CAP_VIDEO_CAPTURE | V4L2_CAP_VIDEO_CAPTURE_MPLANE) != 0,
+        formats,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Kind {
+    Camera,
+    // v4l2loopback nodes, no sensor is behind them
+    VirtualCamera,
+    // IR sensors used for face unlock
+    Biometric,
+    // ALSA capture PCMs opened directly instead of through a sound server
+    Microphone,
+    // Keyboard event nodes and uinput, which is how keyloggers work under Wayland
+    Input,
+    // Index of a user-configured device class
+    Class(usize),
+}
+
+// Which devices a watcher follows, so that its rescans leave those of other watchers alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    All,
+    // Cameras, microphones and input devices, e.g. in the system helper
+    Builtin,
+    // Only the user-configured device classes
+    Classes,
+}
+
+impl Scope {
+    pub fn covers(self, kind: Kind) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Builtin => !matches!(kind, Kind::Class(_)),
+            Scope::Classes => matches!(kind, Kind::Class(_)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Device {
+    // First node of the physical device
+    pub path: PathBuf,
+    pub kind: Kind,
+    pub info: DeviceInfo,
+}
+
+impl Device {
+    pub fn label(&self) -> String {
+        let label = self
+            .info
+            .label()
+            .unwrap_or_else(|| self.path.display().to_string());
+        match &self.info.id_path {
+            Some(id_path) => format!("{label} ({id_path})"),
+            None => label,
+        }
+    }
+}
+
+fn fnmatch(pattern: &OsStr, name: &OsStr) -> bool {
+    let (Ok(pattern), Ok(name)) = (
+        CString::new(pattern.as_bytes()),
+        CString::new(name.as_bytes()),
+    ) else {
+        return false;
+    };
+    unsafe { libc::fnmatch(pattern.as_ptr(), name.as_ptr(), libc::FNM_PATHNAME) == 0 }
+}
+
+// Only the last component of `glob` may contain wildcards
+fn sorted_nodes(glob: &str) -> Vec<PathBuf> {
+    let glob = Path::new(glob);
+    let (Some(dir), Some(pattern)) = (glob.parent(), glob.file_name()) else {
+        return vec![];
+    };
+    let Ok(entries) = read_dir(dir) else {
+        return vec![];
+    };
+    let mut nodes = entries
+        .flatten()
+        .filter(|entry| fnmatch(pattern, &entry.file_name()))
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    // Sort numerically, so that video2 comes before video10
+    nodes.sort_by_key(|path| {
+        let name = path.to_string_lossy();
+        let digits = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+        (digits.len(), name.into_owned())
+    });
+    nodes
+}
+
+// Same heuristic as udev's input_id: keyboards have all of the first 31 keys, from KEY_ESC on.
+// Event nodes are usually only readable by root, but their capabilities are public in sysfs.
+fn is_keyboard(node: &Path) -> bool {
+    let Some(name) = node.file_name() else {
+        return false;
+    };
+    let path = Path::new("/sys/class/input")
+        .join(name)
+        .join("device/capabilities/key");
+    // Words are printed most significant first
+    read_to_string(path)
+        .ok()
+        .and_then(|key| {
+            let word = key.split_whitespace().next_back()?;
+            u64::from_str_radix(word, 16).ok()
         })
-        .unwrap_or_default()
-}
-
-pub fn get_inotify() -> (Inotify, BiHashMap<PathBuf, WatchDescriptor>) {
-    let inotify = Inotify::init().expect("Failed to initialize inotify");
-    inotify
-        .watches()
-        .add("/dev", WatchMask::ATTRIB)
-        .expect("Failed to watch for devices");
-    let mut wd_path = BiHashMap::new();
-    for entry in std::fs::read_dir("/dev").expect("Failed to read /dev") {
-        if let Ok(entry) = entry
-            && entry.file_name().to_string_lossy().starts_with("video")
+        .is_some_and(|bits| bits & 0xFFFF_FFFE == 0xFFFF_FFFE)
+}
+
+// Holders of every device, along with how many handles they have open on it
+fn open_handles(
+    proc: &Path,
+    devices: &HashMap<PathBuf, Device>,
+) -> HashMap<Device, (Vec<Process>, usize)> {
+    let mut open = HashMap::<Device, (Vec<Process>, usize)>::new();
+    for (node, processes) in scan_proc(proc, |path| devices.contains_key(path)) {
+        let (holders, handles) = open.entry(devices[&node].clone()).or_default();
+        *handles += processes.len();
+        for process in processes {
+            if !holders.iter().any(|holder| holder.pid == process.pid) {
+                holders.push(process);
+            }
+        }
+    }
+    open
+}
+
+pub fn open_devices(
+    proc: &Path,
+    devices: &HashMap<PathBuf, Device>,
+) -> HashMap<Device, Vec<Process>> {
+    open_handles(proc, devices)
+        .into_iter()
+        .map(|(device, (holders, _))| (device, holders))
+        .collect()
+}
+
+fn handles(
+    proc: &Path,
+    devices: &HashMap<PathBuf, Device>,
+    device: &Device,
+) -> (Vec<Process>, usize) {
+    let nodes = devices
+        .iter()
+        .filter(|&(_, other)| other == device)
+        .map(|(node, _)| node.as_path())
+        .collect::<Vec<_>>();
+    let mut holders = Vec::<Process>::new();
+    let mut handles = 0;
+    for process in scan_proc(proc, |path| nodes.contains(&path))
+        .into_values()
+        .flatten()
+    {
+        handles += 1;
+        if !holders.iter().any(|holder| holder.pid == process.pid) {
+            holders.push(process);
+        }
+    }
+    (holders, handles)
+}
+
+pub fn holders(proc: &Path, devices: &HashMap<PathBuf, Device>, device: &Device) -> Vec<Process> {
+    handles(proc, devices, device).0
+}
+
+const VIDEO: &str = "/dev/video*";
+// Complex cameras (IPU6 and other MIPI sensors) are a graph of many nodes behind a media device
+const MEDIA: &str = "/dev/media*";
+// pcmC<card>D<device>c, playback devices end with p
+const ALSA_CAPTURE: &str = "/dev/snd/pcmC*c";
+const INPUT: &str = "/dev/input";
+const INPUT_EVENTS: &str = "/dev/input/event*";
+const UINPUT: &str = "/dev/uinput";
+
+pub struct DeviceWatch {
+    pub inotify: Inotify,
+    // Watched nodes, and the directories whose nodes can't be watched one by one
+    pub wd_path: BiHashMap<PathBuf, WatchDescriptor>,
+    // Watched node -> the physical device it belongs to
+    pub devices: HashMap<PathBuf, Device>,
+    // Camera nodes are grouped by bus and card, IR nodes often share both with the webcam
+    cameras: HashMap<(String, String, Kind), Device>,
+    // Video node -> the media device of the pipeline it's part of, and the pipeline's info
+    pipelines: HashMap<PathBuf, (PathBuf, DeviceInfo)>,
+    classes: Vec<DeviceClass>,
+    ir_cameras: Vec<String>,
+    scope: Scope,
+    // Used instead of inotify when privileged, since it reports who opened a node
+    pub fanotify: Option<Fanotify>,
+    // Handles left open according to inotify, which also sees processes /proc doesn't show
+    opens: HashMap<Device, usize>,
+}
+
+impl DeviceWatch {
+    pub fn new(classes: &[DeviceClass], ir_cameras: &[String], scope: Scope) -> Self {
+        let inotify = Inotify::init().expect("Failed to initialize inotify");
+        let fanotify = Fanotify::new()
+            .inspect_err(|err| eprintln!("fanotify unavailable, falling back to inotify: {err}"))
+            .ok();
+        let mut watch = DeviceWatch {
+            inotify,
+            wd_path: BiHashMap::new(),
+            devices: HashMap::new(),
+            cameras: HashMap::new(),
+            pipelines: HashMap::new(),
+            classes: classes.to_vec(),
+            ir_cameras: ir_cameras.to_vec(),
+            scope,
+            fanotify,
+            opens: HashMap::new(),
+        };
+        // Pipelines go first, so that their nodes aren't grouped on their own
+        let builtin = [MEDIA, VIDEO, ALSA_CAPTURE, INPUT_EVENTS, UINPUT]
+            .into_iter()
+            .filter(|_| scope != Scope::Classes)
+            .map(str::to_owned);
+        let classes = classes
+            .iter()
+            .filter(|_| scope != Scope::Builtin)
+            .map(|class| class.glob.clone());
+        for glob in builtin.chain(classes) {
+            for node in sorted_nodes(&glob) {
+                let info = DeviceInfo::lookup(&node);
+                watch.add(&node, info);
+            }
+        }
+        watch
+    }
+
+    fn kind(&self, node: &Path) -> Option<Kind> {
+        let matches = |glob: &str| fnmatch(OsStr::new(glob), node.as_os_str());
+        let kind = if matches(VIDEO) {
+            Kind::Camera
+        } else if matches(ALSA_CAPTURE) {
+            Kind::Microphone
+        } else if (matches(INPUT_EVENTS) && is_keyboard(node)) || matches(UINPUT) {
+            Kind::Input
+        } else {
+            let index = self.classes.iter().position(|class| matches(&class.glob))?;
+            Kind::Class(index)
+        };
+        Some(kind).filter(|&kind| self.scope.covers(kind))
+    }
+
+    // Returns the device the node was added to, if it's one worth watching
+    pub fn add(&mut self, node: &Path, info: DeviceInfo) -> Option<&Device> {
+        if self.devices.contains_key(node) {
+            return self.devices.get(node);
+        }
+        // Media devices only group video nodes, libcamera keeps them open without streaming
+        if fnmatch(OsStr::new(MEDIA), node.as_os_str()) {
+            if self.scope != Scope::Classes {
+                self.add_pipeline(node, info);
+            }
+            return None;
+        }
+        let mut kind = self.kind(node)?;
+        // Capabilities are queried before watching, so that probing doesn't trigger events
+        let caps = if kind == Kind::Camera {
+            let caps = query_capabilities(node)?;
+            kind = caps.kind()?;
+            // Decided per node, before an IR node is grouped with the webcam it belongs to
+            if kind == Kind::Camera && is_ir_camera(&self.ir_cameras, node, info.name.as_deref()) {
+                kind = Kind::Biometric;
+            }
+            Some(caps)
+        } else {
+            None
+        };
+        self.watch(node)?;
+        let mut device = Device {
+            path: node.to_owned(),
+            kind,
+            info,
+        };
+        if let Some(caps) = caps {
+            // Nodes of a pipeline are grouped by its media device, others by bus and card
+            let group = match self.pipelines.get(node) {
+                Some((media, info)) => {
+                    device.info = info.clone();
+                    (media.to_string_lossy().into_owned(), String::new())
+                }
+                None => (caps.bus_info, caps.card),
+            };
+            device = self
+                .cameras
+                .entry((group.0, group.1, kind))
+                .or_insert(device)
+                .clone();
+        }
+        self.devices.insert(node.to_owned(), device);
+        self.devices.get(node)
+    }
+
+    // Media devices without a sensor (e.g. codecs) aren't cameras. uvcvideo registers one for
+    // every webcam, but its nodes can be told apart on their own, e.g. IR and metadata nodes.
+    fn add_pipeline(&mut self, node: &Path, mut info: DeviceInfo) {
+        let Some(media) = query_media(node)
+            .filter(|media| media.driver != "uvcvideo" && !media.sensors.is_empty())
+        else {
+            return;
+        };
+        info.name.get_or_insert(media.model);
+        for member in media.nodes {
+            self.pipelines
+                .insert(member.clone(), (node.to_owned(), info.clone()));
+            // Nodes plugged in before their media device move over to it,
+            // what's left of their old group is dropped by the next reconcile
+            if self.devices.contains_key(&member) {
+                self.remove(&member);
+                let info = DeviceInfo::lookup(&member);
+                self.add(&member, info);
+            }
+        }
+    }
+
+    fn watch(&mut self, node: &Path) -> Option<()> {
+        if let Some(fanotify) = &mut self.fanotify {
+            match fanotify.add(node) {
+                Ok(()) => return Some(()),
+                Err(err) => eprintln!("Failed to mark {} with fanotify: {err}", node.display()),
+            }
+        }
+        // Event nodes normally belong to root, but opens still show up on their directory
+        let path = match node.parent() {
+            Some(dir) if dir == Path::new(INPUT) => dir,
+            _ => node,
+        };
+        if self.wd_path.contains_left(path) {
+            return Some(());
+        }
+        match self
+            .inotify
+            .watches()
+            .add(path, WatchMask::OPEN | WatchMask::CLOSE)
         {
-            let Ok(wd) = inotify.watches().add(
-                entry.path(),
-                WatchMask::OPEN | WatchMask::CLOSE | WatchMask::DELETE_SELF,
-            ) else {
+            Ok(wd) => {
+                self.wd_path.insert(path.to_owned(), wd);
+            }
+            // e.g. /dev/uinput belongs to root, and its directory is /dev with its constant opens
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => eprintln!(
+                "Opens of {} can't be watched, its holders are only found by rescanning /proc",
+                node.display()
+            ),
+            Err(_) => return None,
+        }
+        Some(())
+    }
+
+    // Returns the device once its last node is gone
+    pub fn remove(&mut self, node: &Path) -> Option<Device> {
+        self.pipelines.retain(|_, (media, _)| media != node);
+        let device = self.devices.remove(node)?;
+        if let Some(fanotify) = &mut self.fanotify {
+            fanotify.remove(node);
+        }
+        if let Some((_, wd)) = self.wd_path.remove_by_left(node) {
+            // The kernel already dropped the watch if the node was deleted
+            let _ = self.inotify.watches().remove(wd);
+        }
+        if self.devices.values().any(|other| *other == device) {
+            return None;
+        }
+        self.cameras.retain(|_, camera| *camera != device);
+        self.opens.remove(&device);
+        Some(device)
+    }
+
+    fn count(&mut self, device: &Device, open: bool) {
+        let opens = self.opens.entry(device.clone()).or_default();
+        // Closes of handles opened before the watch was added aren't counted
+        *opens = if open {
+            *opens + 1
+        } else {
+            opens.saturating_sub(1)
+        };
+    }
+
+    // Handles that can't be accounted for in /proc are held by an unknown process
+    fn with_unknown(
+        &self,
+        device: &Device,
+        mut holders: Vec<Process>,
+        handles: usize,
+    ) -> Vec<Process> {
+        if self.opens.get(device).is_some_and(|&opens| opens > handles) {
+            holders.push(Process::unknown());
+        }
+        holders
+    }
+
+    pub fn holders(&self, proc: &Path, device: &Device) -> Vec<Process> {
+        let (holders, handles) = handles(proc, &self.devices, device);
+        self.with_unknown(device, holders, handles)
+    }
+
+    pub fn open_devices(&mut self, proc: &Path, overflowed: bool) -> HashMap<Device, Vec<Process>> {
+        let mut open = open_handles(proc, &self.devices);
+        for (device, opens) in &mut self.opens {
+            let handles = open.get(device).map_or(0, |&(_, handles)| handles);
+            // Opens and closes were lost, only the handles that can be seen are certain
+            if overflowed {
+                *opens = handles.min(*opens);
+            }
+            if *opens > 0 {
+                open.entry(device.clone()).or_default();
+            }
+        }
+        open.into_iter()
+            .map(|(device, (holders, handles))| {
+                let holders = self.with_unknown(&device, holders, handles);
+                (device, holders)
+            })
+            .filter(|(_, holders)| !holders.is_empty())
+            .collect()
+    }
+
+    pub fn device(&self, wd: &WatchDescriptor, name: Option<&OsStr>) -> Option<&Device> {
+        let path = self.wd_path.get_by_right(wd)?;
+        match name {
+            Some(name) => self.devices.get(&path.join(name)),
+            None => self.devices.get(path),
+        }
+    }
+}
+
+// Returns which fds are readable, or None if the timeout ran out before any was
+fn wait_for_events<const N: usize>(fds: [RawFd; N], timeout: Duration) -> Option<[bool; N]> {
+    let mut fds = fds.map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+    loop {
+        match unsafe { libc::poll(fds.as_mut_ptr(), N as libc::nfds_t, timeout) } {
+            0 => return None,
+            res if res > 0 => break,
+            _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => {}
+            // Let the following reads surface the error
+            _ => return Some([true; N]),
+        }
+    }
+    Some(fds.map(|fd| fd.revents != 0))
+}
+
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Returns false once the subscription is gone, so that the watcher can stop
+fn send(output: &mut Sender<Message>, message: Message) -> bool {
+    loop {
+        match output.try_send(message.clone()) {
+            Ok(()) => return true,
+            Err(err) if err.is_disconnected() => return false,
+            Err(_) => eprintln!("Failed to send device event"),
+        }
+    }
+}
+
+pub fn listen(
+    output: Sender<Message>,
+    classes: Vec<DeviceClass>,
+    ir_cameras: Vec<String>,
+    scope: Scope,
+) {
+    // Holders can't be found from inside a sandbox, cameras are then followed through PipeWire
+    if sandboxed() {
+        return;
+    }
+    let hotplug = UdevMonitor::new()
+        .inspect_err(|err| eprintln!("Failed to monitor udev, hotplug won't be tracked: {err}"))
+        .ok();
+    listen_with(output, &classes, &ir_cameras, scope, hotplug);
+}
+
+pub fn listen_with(
+    mut output: Sender<Message>,
+    classes: &[DeviceClass],
+    ir_cameras: &[String],
+    scope: Scope,
+    mut hotplug: Option<impl HotplugSource>,
+) {
+    let proc = Path::new(PROC);
+    let mut watch = DeviceWatch::new(classes, ir_cameras, scope);
+    if !send(
+        &mut output,
+        Message::DevicePrevious(scope, open_devices(proc, &watch.devices)),
+    ) {
+        return;
+    }
+    let mut event_buffer = [0; 4096];
+    // Holders are periodically checked against /proc in case events were missed
+    let mut last_reconcile = Instant::now();
+
+    loop {
+        // poll skips negative fds
+        let fds = [
+            watch.inotify.as_raw_fd(),
+            hotplug.as_ref().map_or(-1, |hotplug| hotplug.as_raw_fd()),
+            watch
+                .fanotify
+                .as_ref()
+                .map_or(-1, |fanotify| fanotify.as_raw_fd()),
+        ];
+        let timeout = RECONCILE_INTERVAL.saturating_sub(last_reconcile.elapsed());
+        let [opened, plugged, accessed] = wait_for_events(fds, timeout).unwrap_or_default();
+
+        if let Some(hotplug) = hotplug.as_mut().filter(|_| plugged) {
+            for event in hotplug.receive() {
+                let message = match event.action {
+                    Action::Add | Action::Change => {
+                        let Some(device) = watch.add(&event.node, event.info).cloned() else {
+                            continue;
+                        };
+                        // It may already have been opened before the watch was added
+                        let holders = watch.holders(proc, &device);
+                        Message::DeviceHolders(device, holders)
+                    }
+                    Action::Remove => {
+                        let Some(device) = watch.remove(&event.node) else {
+                            continue;
+                        };
+                        Message::DeviceReset(device)
+                    }
+                };
+                if !send(&mut output, message) {
+                    return;
+                }
+            }
+        }
+
+        let mut overflowed = false;
+        let events = opened.then(|| {
+            watch
+                .inotify
+                .read_events_blocking(&mut event_buffer)
+                .expect("Failed to read events")
+        });
+        for event in events.into_iter().flatten() {
+            match event.mask {
+                EventMask::Q_OVERFLOW => {
+                    eprintln!("Device events overflowed, rescanning /proc");
+                    overflowed = true;
+                    break;
+                }
+                EventMask::OPEN | EventMask::CLOSE_WRITE | EventMask::CLOSE_NOWRITE => {
+                    let Some(device) = watch.device(&event.wd, event.name).cloned() else {
+                        continue;
+                    };
+                    watch.count(&device, event.mask == EventMask::OPEN);
+                    let holders = watch.holders(proc, &device);
+                    if !send(&mut output, Message::DeviceHolders(device, holders)) {
+                        return;
+                    }
+                }
+                _ => continue,
+            };
+        }
+
+        let accesses = watch
+            .fanotify
+            .as_mut()
+            .filter(|_| accessed)
+            .map(Fanotify::read)
+            .unwrap_or_default();
+        for access in accesses {
+            let (pid, node, open) = match access {
+                Access::Overflow => {
+                    eprintln!("Device events overflowed, rescanning /proc");
+                    overflowed = true;
+                    break;
+                }
+                Access::Open { pid, node } => (pid, node, true),
+                Access::Close { pid, node } => (pid, node, false),
+            };
+            let Some(device) = watch.devices.get(&node) else {
                 continue;
             };
-            wd_path.insert(entry.path(), wd);
+            let mut holders = holders(proc, &watch.devices, device);
+            // The opener is known even if it already closed the node again by the time of the scan
+            let dir = proc.join(pid.to_string());
+            if open
+                && pid != std::process::id()
+                && !holders.iter().any(|holder| holder.pid == pid)
+                && dir.exists()
+            {
+                holders.push(Process::read(&dir, pid));
+            }
+            if !send(&mut output, Message::DeviceHolders(device.clone(), holders)) {
+                return;
+            }
+        }
+
+        if overflowed || last_reconcile.elapsed() >= RECONCILE_INTERVAL {
+            last_reconcile = Instant::now();
+            let devices = watch.open_devices(proc, overflowed);
+            if !send(&mut output, Message::DeviceReconcile(scope, devices)) {
+                return;
+            }
         }
     }
-    (inotify, wd_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::{
+            fd::{FromRawFd, OwnedFd},
+            unix::fs::symlink,
+        },
+        sync::mpsc,
+        time::Instant,
+    };
+
+    use cosmic::iced::futures::{FutureExt, StreamExt, channel::mpsc::channel};
+
+    use super::*;
+    use crate::hotplug::Hotplug;
+
+    // A scratch directory standing in for /proc or /sys, removed when dropped
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("privacy-indicator-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Fixture(dir)
+        }
+
+        // Adds a process to a fake /proc, holding `nodes` open
+        fn process(&self, pid: u32, comm: &str, cgroup: &str, nodes: &[&str]) {
+            let dir = self.0.join(pid.to_string());
+            fs::create_dir_all(dir.join("fd")).unwrap();
+            fs::write(dir.join("comm"), format!("{comm}\n")).unwrap();
+            fs::write(dir.join("cmdline"), format!("{comm}\0--verbose\0")).unwrap();
+            fs::write(dir.join("cgroup"), format!("0::{cgroup}\n")).unwrap();
+            for (fd, node) in nodes.iter().enumerate() {
+                symlink(node, dir.join("fd").join((fd + 3).to_string())).unwrap();
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn device(node: &str, kind: Kind) -> Device {
+        Device {
+            path: PathBuf::from(node),
+            kind,
+            info: DeviceInfo::default(),
+        }
+    }
+
+    // Two camera nodes of the same webcam and an ALSA capture PCM
+    fn devices() -> HashMap<PathBuf, Device> {
+        let camera = device("/dev/video0", Kind::Camera);
+        HashMap::from([
+            (PathBuf::from("/dev/video0"), camera.clone()),
+            (PathBuf::from("/dev/video1"), camera),
+            (
+                PathBuf::from("/dev/snd/pcmC0D0c"),
+                device("/dev/snd/pcmC0D0c", Kind::Microphone),
+            ),
+        ])
+    }
+
+    fn fake_proc(name: &str) -> Fixture {
+        let proc = Fixture::new(name);
+        let scope = "/user.slice/user-1000.slice/user@1000.service/app.slice";
+        proc.process(
+            100,
+            "cheese",
+            &format!("{scope}/app-gnome-org.gnome.Cheese-1234.scope"),
+            &["/dev/video0", "/dev/video1", "/dev/null"],
+        );
+        proc.process(101, "arecord", "/user.slice", &["/dev/snd/pcmC0D0c"]);
+        proc.process(102, "bash", "/user.slice", &["/dev/null", "/dev/pts/0"]);
+        // Our own queries don't count
+        proc.process(std::process::id(), "test", "/", &["/dev/video0"]);
+        fs::create_dir_all(proc.0.join("self")).unwrap();
+        proc
+    }
+
+    fn pids(processes: &[Process]) -> Vec<u32> {
+        let mut pids = processes.iter().map(|p| p.pid).collect::<Vec<_>>();
+        pids.sort_unstable();
+        pids
+    }
+
+    #[test]
+    fn open_devices_groups_nodes_by_device() {
+        let proc = fake_proc("open-devices");
+        let devices = devices();
+        let open = open_devices(&proc.0, &devices);
+
+        assert_eq!(open.len(), 2);
+        let camera = &open[&device("/dev/video0", Kind::Camera)];
+        assert_eq!(pids(camera), [100]);
+        let microphone = &open[&device("/dev/snd/pcmC0D0c", Kind::Microphone)];
+        assert_eq!(pids(microphone), [101]);
+    }
+
+    #[test]
+    fn holders_reads_process_details() {
+        let proc = fake_proc("holders");
+        let devices = devices();
+        let holders = holders(&proc.0, &devices, &device("/dev/video0", Kind::Camera));
+
+        assert_eq!(
+            holders,
+            [Process {
+                pid: 100,
+                comm: "cheese".to_owned(),
+                exe: None,
+                cmdline: vec!["cheese".to_owned(), "--verbose".to_owned()],
+                app_id: Some("org.gnome.Cheese".to_owned()),
+                uid: Some(unsafe { libc::geteuid() }),
+            }]
+        );
+        let unused = device("/dev/video4", Kind::Camera);
+        assert!(super::holders(&proc.0, &devices, &unused).is_empty());
+    }
+
+    #[test]
+    fn opens_of_hidden_processes_are_held_by_an_unknown_process() {
+        let dev = Fixture::new("hidden-dev");
+        let node = dev.0.join("sensor0");
+        fs::write(&node, "").unwrap();
+        let classes = [DeviceClass {
+            label: "Sensor".to_owned(),
+            glob: dev.0.join("sensor*").to_string_lossy().into_owned(),
+            icon: "dialog-warning-symbolic".to_owned(),
+        }];
+        let mut watch = DeviceWatch::new(&classes, &[], Scope::All);
+        let device = watch.devices[&node].clone();
+        let proc = Fixture::new("hidden-proc");
+        let node = node.to_str().unwrap();
+
+        // Opened by a process that /proc doesn't show
+        watch.count(&device, true);
+        assert_eq!(watch.holders(&proc.0, &device), [Process::unknown()]);
+        assert_eq!(
+            watch.open_devices(&proc.0, false)[&device],
+            [Process::unknown()]
+        );
+
+        // A visible holder doesn't account for the hidden one
+        proc.process(100, "cheese", "/user.slice", &[node]);
+        watch.count(&device, true);
+        assert_eq!(pids(&watch.holders(&proc.0, &device)), [0, 100]);
+
+        watch.count(&device, false);
+        assert_eq!(pids(&watch.holders(&proc.0, &device)), [100]);
+        watch.count(&device, false);
+        watch.count(&device, false);
+        fs::remove_dir_all(proc.0.join("100")).unwrap();
+        assert!(watch.holders(&proc.0, &device).is_empty());
+        assert!(watch.open_devices(&proc.0, false).is_empty());
+
+        // Counts are only trusted as far as /proc confirms them after an overflow
+        watch.count(&device, true);
+        assert!(!watch.open_devices(&proc.0, true).contains_key(&device));
+        assert!(watch.holders(&proc.0, &device).is_empty());
+    }
+
+    #[test]
+    fn app_ids_from_cgroups() {
+        let app_id = |unit: &str| app_id_from_cgroup(&format!("0::/app.slice/{unit}"));
+        assert_eq!(
+            app_id("app-flatpak-com.obsproject.Studio-5678.scope").as_deref(),
+            Some("com.obsproject.Studio")
+        );
+        assert_eq!(
+            app_id("app-org.kde.kamoso@autostart.service").as_deref(),
+            Some("org.kde.kamoso")
+        );
+        assert_eq!(
+            app_id("app-gnome-com.example.my\\x2dapp-42.scope").as_deref(),
+            Some("com.example.my-app")
+        );
+        assert_eq!(app_id("session-2.scope"), None);
+    }
+
+    // A USB webcam in a fake /sys, with the given runtime PM state
+    fn fake_sys(name: &str, control: &str, status: &str) -> Fixture {
+        let sys = Fixture::new(name);
+        let usb = sys.0.join("devices/pci0000:00/0000:00:14.0/usb1/1-6");
+        let interface = usb.join("1-6:1.0");
+        fs::create_dir_all(&interface).unwrap();
+        fs::create_dir_all(usb.join("power")).unwrap();
+        fs::write(usb.join("idVendor"), "046d\n").unwrap();
+        fs::write(usb.join("power/control"), format!("{control}\n")).unwrap();
+        fs::write(usb.join("power/runtime_status"), format!("{status}\n")).unwrap();
+        let class = sys.0.join("class/video4linux/video0");
+        fs::create_dir_all(&class).unwrap();
+        symlink(&interface, class.join("device")).unwrap();
+        sys
+    }
+
+    #[test]
+    fn runtime_pm_tells_whether_a_camera_streams() {
+        let node = Path::new("/dev/video0");
+        let sys = fake_sys("runtime-suspended", "auto", "suspended");
+        assert_eq!(
+            runtime_active(&sys.0.canonicalize().unwrap(), node),
+            Some(false)
+        );
+        let sys = fake_sys("runtime-active", "auto", "active");
+        assert_eq!(
+            runtime_active(&sys.0.canonicalize().unwrap(), node),
+            Some(true)
+        );
+        let sys = fake_sys("runtime-resuming", "auto", "resuming");
+        assert_eq!(
+            runtime_active(&sys.0.canonicalize().unwrap(), node),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn runtime_pm_without_autosuspend_is_unknown() {
+        let sys = fake_sys("runtime-on", "on", "active");
+        let sys = sys.0.canonicalize().unwrap();
+        assert_eq!(runtime_active(&sys, Path::new("/dev/video0")), None);
+        // Unknown nodes and cameras without a USB device behind them can't tell either
+        assert_eq!(runtime_active(&sys, Path::new("/dev/video2")), None);
+        fs::remove_file(sys.join("devices/pci0000:00/0000:00:14.0/usb1/1-6/idVendor")).unwrap();
+        assert_eq!(runtime_active(&sys, Path::new("/dev/video0")), None);
+    }
+
+    // Hotplug events queued by the test, the pipe wakes up poll like the udev socket would
+    struct FakeHotplug {
+        events: mpsc::Receiver<Hotplug>,
+        wake: OwnedFd,
+    }
+
+    struct FakeUdev {
+        events: mpsc::Sender<Hotplug>,
+        wake: OwnedFd,
+    }
+
+    impl FakeUdev {
+        fn new() -> (Self, FakeHotplug) {
+            let mut fds = [0; 2];
+            let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) };
+            assert_eq!(res, 0);
+            let (read, write) =
+                unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+            let (sender, receiver) = mpsc::channel();
+            let udev = FakeUdev {
+                events: sender,
+                wake: write,
+            };
+            let source = FakeHotplug {
+                events: receiver,
+                wake: read,
+            };
+            (udev, source)
+        }
+
+        fn send(&self, action: Action, node: &Path) {
+            let info = DeviceInfo {
+                name: Some("Fake sensor".to_owned()),
+                ..Default::default()
+            };
+            self.events
+                .send(Hotplug {
+                    action,
+                    node: node.to_owned(),
+                    info,
+                })
+                .unwrap();
+            let res = unsafe { libc::write(self.wake.as_raw_fd(), [0u8].as_ptr().cast(), 1) };
+            assert_eq!(res, 1);
+        }
+    }
+
+    impl AsRawFd for FakeHotplug {
+        fn as_raw_fd(&self) -> RawFd {
+            self.wake.as_raw_fd()
+        }
+    }
+
+    impl HotplugSource for FakeHotplug {
+        fn receive(&mut self) -> Vec<Hotplug> {
+            let mut buffer = [0u8; 64];
+            while unsafe { libc::read(self.wake.as_raw_fd(), buffer.as_mut_ptr().cast(), 64) } > 0 {
+            }
+            self.events.try_iter().collect()
+        }
+    }
+
+    // Waits for the first matching message, real devices of the machine may show up too
+    fn next(
+        receiver: &mut cosmic::iced::futures::channel::mpsc::Receiver<Message>,
+        matches: impl Fn(&Message) -> bool,
+    ) -> Message {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            match receiver.next().now_or_never() {
+                Some(Some(message)) if matches(&message) => return message,
+                Some(Some(_)) => {}
+                Some(None) => panic!("Device watcher stopped"),
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        panic!("Timed out waiting for the device watcher");
+    }
+
+    fn about(node: &Path) -> impl Fn(&Message) -> bool {
+        move |message| match message {
+            Message::DeviceHolders(device, _) | Message::DeviceReset(device) => device.path == node,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn hotplugged_devices_are_watched_until_removed() {
+        let dev = Fixture::new("hotplug");
+        let classes = vec![DeviceClass {
+            label: "Sensor".to_owned(),
+            glob: dev.0.join("sensor*").to_string_lossy().into_owned(),
+            icon: "dialog-warning-symbolic".to_owned(),
+        }];
+        let (udev, source) = FakeUdev::new();
+        let (output, mut receiver) = channel(100);
+        let watcher = std::thread::spawn(move || {
+            listen_with(output, &classes, &[], Scope::All, Some(source))
+        });
+
+        // Plugged in after the initial scan
+        next(&mut receiver, |message| {
+            matches!(message, Message::DevicePrevious(..))
+        });
+        let node = dev.0.join("sensor0");
+        fs::write(&node, "").unwrap();
+        udev.send(Action::Add, &node);
+        let Message::DeviceHolders(device, holders) = next(&mut receiver, about(&node)) else {
+            panic!("Expected the holders of {}", node.display());
+        };
+        assert_eq!(device.kind, Kind::Class(0));
+        assert_eq!(device.info.name.as_deref(), Some("Fake sensor"));
+        assert!(holders.is_empty());
+
+        fs::remove_file(&node).unwrap();
+        udev.send(Action::Remove, &node);
+        let Message::DeviceReset(removed) = next(&mut receiver, about(&node)) else {
+            panic!("Expected {} to be reset", node.display());
+        };
+        assert_eq!(removed, device);
+
+        // The watcher stops once nobody listens anymore
+        drop(receiver);
+        fs::write(dev.0.join("sensor1"), "").unwrap();
+        udev.send(Action::Add, &dev.0.join("sensor1"));
+        watcher.join().unwrap();
+    }
 }
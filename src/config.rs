@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use cosmic::cosmic_config::{self, CosmicConfigEntry, cosmic_config_derive::CosmicConfigEntry};
+use serde::{Deserialize, Serialize};
+
+use crate::stream::Stream;
+
+// Extra device nodes to watch, reported as their own indicator while held open
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceClass {
+    pub label: String,
+    // Only the file name may contain wildcards, e.g. `/dev/hidraw*`
+    pub glob: String,
+    pub icon: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, CosmicConfigEntry)]
+#[version = 1]
+pub struct Config {
+    // Applications matched by name, binary or flatpak id
+    pub ignored_apps: Vec<String>,
+    // Reported even when their streams look harmless
+    pub allowed_apps: Vec<String>,
+    // Keeps short accesses visible for at least this long
+    pub min_display_ms: u64,
+    // Camera and ALSA capture opens shorter than this are considered device probing
+    pub camera_min_hold_ms: u64,
+    // Processes that only open cameras to enumerate them
+    pub probe_processes: Vec<String>,
+    // v4l2loopback devices get their own indicator, or are ignored when false
+    pub show_virtual_cameras: bool,
+    // Cameras, by name or node, to report as biometric when their formats don't give them away.
+    // Devices reported by the system helper can't be overridden, it doesn't know the config.
+    pub ir_cameras: Vec<String>,
+    // Sound servers holding ALSA capture devices, their clients show up as PipeWire streams
+    pub sound_servers: Vec<String>,
+    // Processes expected to read keyboards or inject input, like the compositor
+    pub input_servers: Vec<String>,
+    pub device_classes: Vec<DeviceClass>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ignored_apps: vec![
+                "cosmic-osd".into(),
+                "cosmic-settings".into(),
+                "pavucontrol".into(),
+            ],
+            allowed_apps: vec![],
+            min_display_ms: 3000,
+            camera_min_hold_ms: 500,
+            probe_processes: vec![
+                "systemd-udevd".into(),
+                "udevadm".into(),
+                "v4l_id".into(),
+                "wireplumber".into(),
+            ],
+            show_virtual_cameras: true,
+            ir_cameras: vec![],
+            sound_servers: vec![
+                "pipewire".into(),
+                "pipewire-pulse".into(),
+                "wireplumber".into(),
+                "pulseaudio".into(),
+                "jackd".into(),
+                "jackdbus".into(),
+            ],
+            input_servers: vec![
+                "cosmic-comp".into(),
+                "systemd-logind".into(),
+                "systemd-udevd".into(),
+                "Xorg".into(),
+                "gnome-shell".into(),
+                "kwin_wayland".into(),
+                "sway".into(),
+                "acpid".into(),
+            ],
+            device_classes: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn load(id: &str) -> Self {
+        cosmic_config::Config::new(id, Self::VERSION)
+            .map(|context| match Self::get_entry(&context) {
+                Ok(config) => config,
+                Err((errors, config)) => {
+                    for err in errors {
+                        eprintln!("Failed to load config: {err}");
+                    }
+                    config
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn reports(&self, stream: &Stream) -> bool {
+        let matches = |apps: &[String]| apps.iter().any(|app| stream.app.matches(app));
+        matches(&self.allowed_apps) || !(stream.harmless || matches(&self.ignored_apps))
+    }
+}
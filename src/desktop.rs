@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    env,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DesktopEntry {
+    pub name: Option<String>,
+    // Icon name from the theme, or an absolute path
+    pub icon: Option<String>,
+}
+
+fn data_dirs() -> Vec<PathBuf> {
+    let home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    let dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| "/usr/local/share:/usr/share".to_owned());
+    home.into_iter()
+        .chain(dirs.split(':').map(PathBuf::from))
+        .collect()
+}
+
+fn parse(contents: &str) -> DesktopEntry {
+    let mut entry = DesktopEntry::default();
+    let mut main_group = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            main_group = line == "[Desktop Entry]";
+            continue;
+        }
+        if !main_group {
+            continue;
+        }
+        // Localized keys like Name[de] don't match, the untranslated value is used
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = Some(value.trim().to_owned()).filter(|value| !value.is_empty());
+        match key.trim() {
+            "Name" => entry.name = value,
+            "Icon" => entry.icon = value,
+            _ => {}
+        }
+    }
+    entry
+}
+
+// Finds the .desktop file of an app id, e.g. from a Flatpak or a systemd scope
+pub fn lookup(app_id: &str) -> Option<DesktopEntry> {
+    if app_id.is_empty() || app_id.contains('/') {
+        return None;
+    }
+    data_dirs().into_iter().find_map(|dir| {
+        let path = dir.join("applications").join(format!("{app_id}.desktop"));
+        read_to_string(path).ok().map(|contents| parse(&contents))
+    })
+}
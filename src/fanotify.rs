@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Access {
+    Open { pid: u32, node: PathBuf },
+    Close { pid: u32, node: PathBuf },
+    // Events were dropped, holders have to be rescanned
+    Overflow,
+}
+
+// Unlike inotify, fanotify tells which process opened a node, but it needs CAP_SYS_ADMIN.
+// Nodes are reported by file handle, since opening devices to hand out fds could wake them up.
+pub struct Fanotify {
+    fd: OwnedFd,
+    // (handle type, handle) -> node
+    handles: HashMap<(i32, Vec<u8>), PathBuf>,
+}
+
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+fn handle(path: &Path) -> io::Result<(i32, Vec<u8>)> {
+    let path = path_cstring(path)?;
+    let header = size_of::<libc::file_handle>();
+    // file_handle is followed by up to MAX_HANDLE_SZ bytes, u32s keep it aligned
+    let mut buffer = vec![0u32; (header + libc::MAX_HANDLE_SZ as usize).div_ceil(4)];
+    let file_handle = buffer.as_mut_ptr().cast::<libc::file_handle>();
+    unsafe { (*file_handle).handle_bytes = libc::MAX_HANDLE_SZ as _ };
+    let mut mount_id = 0;
+    let res = unsafe {
+        libc::name_to_handle_at(libc::AT_FDCWD, path.as_ptr(), file_handle, &mut mount_id, 0)
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (len, kind) = unsafe { ((*file_handle).handle_bytes, (*file_handle).handle_type) };
+    let bytes =
+        unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), header + len as usize) };
+    Ok((kind, bytes[header..].to_vec()))
+}
+
+impl Fanotify {
+    pub fn new() -> io::Result<Self> {
+        let flags =
+            libc::FAN_CLASS_NOTIF | libc::FAN_CLOEXEC | libc::FAN_NONBLOCK | libc::FAN_REPORT_FID;
+        let fd = unsafe { libc::fanotify_init(flags, (libc::O_RDONLY | libc::O_CLOEXEC) as _) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Fanotify {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            handles: HashMap::new(),
+        })
+    }
+
+    fn mark(&self, flags: libc::c_uint, node: &Path) -> io::Result<()> {
+        let path = path_cstring(node)?;
+        let mask = libc::FAN_OPEN | libc::FAN_CLOSE;
+        let res = unsafe {
+            libc::fanotify_mark(
+                self.fd.as_raw_fd(),
+                flags,
+                mask,
+                libc::AT_FDCWD,
+                path.as_ptr(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, node: &Path) -> io::Result<()> {
+        // Filesystems without file handles can't be reported with FAN_REPORT_FID
+        let handle = handle(node)?;
+        self.mark(libc::FAN_MARK_ADD, node)?;
+        self.handles.insert(handle, node.to_owned());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, node: &Path) {
+        // The kernel already dropped the mark if the node was deleted
+        let _ = self.mark(libc::FAN_MARK_REMOVE, node);
+        self.handles.retain(|_, path| path != node);
+    }
+
+    // Reads the queued events without blocking
+    pub fn read(&mut self) -> Vec<Access> {
+        let mut buffer = vec![0u64; 512];
+        let len = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len() * size_of::<u64>(),
+            )
+        };
+        let Ok(len) = usize::try_from(len) else {
+            return vec![];
+        };
+        let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), len) };
+
+        let mut accesses = vec![];
+        let mut offset = 0;
+        let metadata_size = size_of::<libc::fanotify_event_metadata>();
+        while offset + metadata_size <= len {
+            let metadata = unsafe {
+                bytes[offset..]
+                    .as_ptr()
+                    .cast::<libc::fanotify_event_metadata>()
+                    .read_unaligned()
+            };
+            let event_len = metadata.event_len as usize;
+            if metadata.vers != libc::FANOTIFY_METADATA_VERSION || event_len < metadata_size {
+                break;
+            }
+            let event = &bytes[offset..(offset + event_len).min(len)];
+            offset += event_len;
+
+            if metadata.mask & libc::FAN_Q_OVERFLOW != 0 {
+                accesses.push(Access::Overflow);
+                continue;
+            }
+            let Some(node) = self.node(&event[metadata.metadata_len as usize..]) else {
+                continue;
+            };
+            let pid = metadata.pid as u32;
+            if metadata.mask & libc::FAN_OPEN != 0 {
+                accesses.push(Access::Open {
+                    pid,
+                    node: node.clone(),
+                });
+            }
+            if metadata.mask & libc::FAN_CLOSE != 0 {
+                accesses.push(Access::Close { pid, node });
+            }
+        }
+        accesses
+    }
+
+    // Finds the node of the fid info record following the event metadata
+    fn node(&self, info: &[u8]) -> Option<PathBuf> {
+        let header = size_of::<libc::fanotify_event_info_fid>();
+        let handle_header = size_of::<libc::file_handle>();
+        if info.len() < header + handle_header || info[0] != libc::FAN_EVENT_INFO_TYPE_FID {
+            return None;
+        }
+        let file_handle = unsafe {
+            info[header..]
+                .as_ptr()
+                .cast::<libc::file_handle>()
+                .read_unaligned()
+        };
+        let start = header + handle_header;
+        let bytes = info.get(start..start + file_handle.handle_bytes as usize)?;
+        self.handles
+            .get(&(file_handle.handle_type, bytes.to_vec()))
+            .cloned()
+    }
+}
+
+impl AsRawFd for Fanotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
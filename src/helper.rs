@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use cosmic::iced::futures::{
+    SinkExt, StreamExt,
+    channel::mpsc::{self, Sender},
+};
+use serde::{Deserialize, Serialize};
+use zbus::{
+    Connection, connection, fdo, interface,
+    message::Header,
+    names::{BusName, UniqueName},
+    object_server::SignalEmitter,
+    proxy,
+};
+
+use crate::{
+    applet::Message,
+    camera::{self, Device, Process, Scope},
+};
+
+const NAME: &str = "dev.DBrox.CosmicPrivacyIndicator.Helper";
+const PATH: &str = "/dev/DBrox/CosmicPrivacyIndicator/Helper";
+
+// Device events as sent over the bus, RON encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Update {
+    Holders(Device, Vec<Process>),
+    Reconcile(HashMap<Device, Vec<Process>>),
+    Reset(Device),
+}
+
+// Other users' processes are exactly what hidepid hides,
+// all that a user learns about them is that the device is in use
+fn redact(processes: &[Process], uid: u32) -> Vec<Process> {
+    let mut redacted = Vec::<Process>::new();
+    for process in processes {
+        let process = if process.uid == Some(uid) {
+            process.clone()
+        } else {
+            Process::unknown()
+        };
+        if !redacted.contains(&process) {
+            redacted.push(process);
+        }
+    }
+    redacted
+}
+
+fn redact_all(devices: &HashMap<Device, Vec<Process>>, uid: u32) -> HashMap<Device, Vec<Process>> {
+    devices
+        .iter()
+        .map(|(device, processes)| (device.clone(), redact(processes, uid)))
+        .collect()
+}
+
+impl Update {
+    fn from_message(message: Message) -> Option<Self> {
+        match message {
+            Message::DeviceHolders(device, processes) => Some(Update::Holders(device, processes)),
+            Message::DevicePrevious(_, devices) | Message::DeviceReconcile(_, devices) => {
+                Some(Update::Reconcile(devices))
+            }
+            Message::DeviceReset(device) => Some(Update::Reset(device)),
+            _ => None,
+        }
+    }
+
+    fn into_message(self) -> Message {
+        match self {
+            Update::Holders(device, processes) => Message::DeviceHolders(device, processes),
+            Update::Reconcile(devices) => Message::DeviceReconcile(Scope::Builtin, devices),
+            Update::Reset(device) => Message::DeviceReset(device),
+        }
+    }
+
+    fn for_user(&self, uid: u32) -> Self {
+        match self {
+            Update::Holders(device, processes) => {
+                Update::Holders(device.clone(), redact(processes, uid))
+            }
+            Update::Reconcile(devices) => Update::Reconcile(redact_all(devices, uid)),
+            Update::Reset(device) => Update::Reset(device.clone()),
+        }
+    }
+
+    fn apply(&self, devices: &mut HashMap<Device, Vec<Process>>) {
+        match self {
+            Update::Holders(device, processes) if processes.is_empty() => {
+                devices.remove(device);
+            }
+            Update::Holders(device, processes) => {
+                devices.insert(device.clone(), processes.clone());
+            }
+            Update::Reconcile(current) => devices.clone_from(current),
+            Update::Reset(device) => {
+                devices.remove(device);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    // Holders of every watched device, handed to applets when they subscribe
+    devices: HashMap<Device, Vec<Process>>,
+    // Bus name of every subscribed applet -> the user it runs as
+    subscribers: HashMap<UniqueName<'static>, u32>,
+}
+
+struct HelperService {
+    state: Arc<Mutex<State>>,
+}
+
+#[interface(name = "dev.DBrox.CosmicPrivacyIndicator.Helper")]
+impl HelperService {
+    // Returns the current holders, changes are then sent to the caller alone
+    async fn subscribe(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> fdo::Result<String> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::Failed("Caller has no bus name".to_owned()))?
+            .to_owned();
+        let uid = fdo::DBusProxy::new(connection)
+            .await?
+            .get_connection_unix_user(sender.clone().into())
+            .await?;
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.insert(sender, uid);
+        let devices = redact_all(&state.devices, uid);
+        ron::to_string(&devices).map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    #[zbus(signal)]
+    async fn changed(emitter: &SignalEmitter<'_>, update: &str) -> zbus::Result<()>;
+}
+
+#[proxy(
+    interface = "dev.DBrox.CosmicPrivacyIndicator.Helper",
+    default_service = "dev.DBrox.CosmicPrivacyIndicator.Helper",
+    default_path = "/dev/DBrox/CosmicPrivacyIndicator/Helper"
+)]
+trait Helper {
+    fn subscribe(&self) -> zbus::Result<String>;
+
+    #[zbus(signal)]
+    fn changed(&self, update: String) -> zbus::Result<()>;
+}
+
+// Runs as root on the system bus, where /proc isn't restricted by hidepid.
+// Device classes are configured per user, so only the built-in devices are watched.
+pub fn run() -> zbus::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let state = Arc::new(Mutex::new(State::default()));
+        let service = HelperService {
+            state: state.clone(),
+        };
+        let connection = connection::Builder::system()?
+            .name(NAME)?
+            .serve_at(PATH, service)?
+            .build()
+            .await?;
+
+        // Applets that went away are unsubscribed
+        let mut owners = fdo::DBusProxy::new(&connection)
+            .await?
+            .receive_name_owner_changed()
+            .await?;
+        tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Some(change) = owners.next().await {
+                    let Ok(args) = change.args() else {
+                        continue;
+                    };
+                    if let BusName::Unique(name) = args.name()
+                        && args.new_owner().is_none()
+                    {
+                        let mut state = state.lock().unwrap();
+                        state.subscribers.retain(|subscriber, _| subscriber != name);
+                    }
+                }
+            }
+        });
+
+        let (output, mut messages) = mpsc::channel(100);
+        std::thread::spawn(move || camera::listen(output, Vec::new(), Vec::new(), Scope::Builtin));
+        while let Some(message) = messages.next().await {
+            let Some(update) = Update::from_message(message) else {
+                continue;
+            };
+            let subscribers = {
+                let mut state = state.lock().unwrap();
+                update.apply(&mut state.devices);
+                state.subscribers.clone()
+            };
+            // Signals are unicast, a broadcast could be received by anyone on the bus
+            for (subscriber, uid) in subscribers {
+                let emitter =
+                    SignalEmitter::new(&connection, PATH)?.set_destination(subscriber.into());
+                let result = match ron::to_string(&update.for_user(uid)) {
+                    Ok(update) => HelperService::changed(&emitter, &update).await,
+                    Err(err) => Err(zbus::Error::Failure(err.to_string())),
+                };
+                if let Err(err) = result {
+                    eprintln!("Failed to send device event: {err}");
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// Forwards the device events of the system helper, returns once the applet is gone
+pub async fn subscribe(output: &mut Sender<Message>) -> zbus::Result<()> {
+    let connection = Connection::system().await?;
+    let helper = HelperProxy::new(&connection).await?;
+    // Subscribed first, so that nothing happening while the holders are read is missed
+    let mut changes = helper.receive_changed().await?;
+    let devices = ron::from_str(&helper.subscribe().await?)
+        .map_err(|err| zbus::Error::Failure(err.to_string()))?;
+    if output
+        .send(Message::DevicePrevious(Scope::Builtin, devices))
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+    while let Some(change) = changes.next().await {
+        let args = change.args()?;
+        let update = match ron::from_str::<Update>(&args.update) {
+            Ok(update) => update,
+            Err(err) => {
+                eprintln!("Invalid device event from the system helper: {err}");
+                continue;
+            }
+        };
+        if output.send(update.into_message()).await.is_err() {
+            return Ok(());
+        }
+    }
+    Err(zbus::Error::Failure("System helper went away".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(pid: u32, uid: u32) -> Process {
+        Process {
+            pid,
+            comm: format!("app{pid}"),
+            exe: None,
+            cmdline: vec![],
+            app_id: None,
+            uid: Some(uid),
+        }
+    }
+
+    #[test]
+    fn other_users_processes_are_redacted() {
+        let processes = [process(100, 1000), process(200, 1001), process(300, 0)];
+        assert_eq!(
+            redact(&processes, 1000),
+            [process(100, 1000), Process::unknown()]
+        );
+        assert_eq!(redact(&processes, 1002), [Process::unknown()]);
+        assert!(redact(&[], 1000).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    ffi::OsStr,
+    io,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use udev::{DeviceType, EventType, MonitorBuilder, MonitorSocket};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub id_path: Option<String>,
+    // The sysfs name, e.g. /sys/class/video4linux/video0/name
+    pub name: Option<String>,
+}
+
+impl DeviceInfo {
+    fn from_udev(device: &udev::Device) -> Self {
+        let string =
+            |value: Option<&OsStr>| value.map(|value| value.to_string_lossy().into_owned());
+        // The hwdb names are the readable ones, the fallbacks come from the USB descriptors
+        let property = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| string(device.property_value(name)))
+                .map(|value| value.replace('_', " "))
+        };
+        let name = string(device.attribute_value("name"))
+            .or_else(|| string(device.parent()?.attribute_value("name")));
+        DeviceInfo {
+            vendor: property(&["ID_VENDOR_FROM_DATABASE", "ID_VENDOR"]),
+            model: property(&["ID_MODEL_FROM_DATABASE", "ID_MODEL"]),
+            id_path: string(device.property_value("ID_PATH")),
+            name: name.map(|name| name.trim().to_owned()),
+        }
+    }
+
+    pub fn lookup(node: &Path) -> Self {
+        let Ok(metadata) = node.metadata() else {
+            return Self::default();
+        };
+        udev::Device::from_devnum(DeviceType::Character, metadata.rdev())
+            .map(|device| Self::from_udev(&device))
+            .unwrap_or_default()
+    }
+
+    pub fn label(&self) -> Option<String> {
+        if let Some(name) = &self.name {
+            return Some(name.clone());
+        }
+        match (&self.vendor, &self.model) {
+            (Some(vendor), Some(model)) => Some(format!("{vendor} {model}")),
+            (vendor, model) => vendor.clone().or_else(|| model.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Change,
+    Remove,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hotplug {
+    pub action: Action,
+    pub node: PathBuf,
+    pub info: DeviceInfo,
+}
+
+// Where device nodes coming and going are reported, so that udev can be swapped for a fake
+pub trait HotplugSource: AsRawFd {
+    // Drains the queued events without blocking, the fd is readable while there are some
+    fn receive(&mut self) -> Vec<Hotplug>;
+}
+
+pub struct UdevMonitor {
+    socket: MonitorSocket,
+}
+
+impl UdevMonitor {
+    pub fn new() -> io::Result<Self> {
+        let socket = MonitorBuilder::new()?.listen()?;
+        Ok(UdevMonitor { socket })
+    }
+}
+
+impl AsRawFd for UdevMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl HotplugSource for UdevMonitor {
+    fn receive(&mut self) -> Vec<Hotplug> {
+        self.socket
+            .iter()
+            .filter_map(|event| {
+                let action = match event.event_type() {
+                    EventType::Add => Action::Add,
+                    EventType::Change => Action::Change,
+                    EventType::Remove => Action::Remove,
+                    _ => return None,
+                };
+                let device = event.device();
+                // Only devices with a node can be held open
+                let node = device.devnode()?.to_owned();
+                Some(Hotplug {
+                    action,
+                    node,
+                    info: DeviceInfo::from_udev(&device),
+                })
+            })
+            .collect()
+    }
+}
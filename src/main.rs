@@ -2,8 +2,24 @@
 
 mod applet;
 mod camera;
+mod config;
+mod desktop;
+mod fanotify;
+mod helper;
+mod hotplug;
+mod media;
 mod rec_icon;
+mod session;
+mod stream;
 
 fn main() -> cosmic::iced::Result {
+    // Started by D-Bus activation on the system bus, see res/
+    if std::env::args().nth(1).as_deref() == Some("--system-helper") {
+        if let Err(err) = helper::run() {
+            eprintln!("System helper failed: {err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     cosmic::applet::run::<applet::PrivacyIndicator>(())
 }
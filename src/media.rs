@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    fs::{File, read_to_string},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+// _IOWR('|', 0x00, struct media_device_info)
+const MEDIA_IOC_DEVICE_INFO: u64 = 0xC100_7C00;
+// _IOWR('|', 0x04, struct media_v2_topology)
+const MEDIA_IOC_G_TOPOLOGY: u64 = 0xC048_7C04;
+const MEDIA_ENT_F_CAM_SENSOR: u32 = 0x0002_0001;
+const MEDIA_INTF_T_V4L_VIDEO: u32 = 0x0000_0200;
+
+#[repr(C)]
+struct MediaDeviceInfo {
+    driver: [u8; 16],
+    model: [u8; 32],
+    serial: [u8; 40],
+    bus_info: [u8; 32],
+    media_version: u32,
+    hw_revision: u32,
+    driver_version: u32,
+    reserved: [u32; 31],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MediaV2Topology {
+    topology_version: u64,
+    num_entities: u32,
+    reserved1: u32,
+    ptr_entities: u64,
+    num_interfaces: u32,
+    reserved2: u32,
+    ptr_interfaces: u64,
+    num_pads: u32,
+    reserved3: u32,
+    ptr_pads: u64,
+    num_links: u32,
+    reserved4: u32,
+    ptr_links: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct MediaV2Entity {
+    id: u32,
+    name: [u8; 64],
+    function: u32,
+    flags: u32,
+    reserved: [u32; 5],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct MediaV2Interface {
+    id: u32,
+    intf_type: u32,
+    flags: u32,
+    reserved: [u32; 9],
+    // struct media_v2_intf_devnode, padded to the size of the union
+    major: u32,
+    minor: u32,
+    raw: [u32; 14],
+}
+
+// A media controller device and the nodes of its pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaDevice {
+    pub driver: String,
+    pub model: String,
+    // Video nodes, some of them are opened by libcamera or a HAL to stream from the pipeline
+    pub nodes: Vec<PathBuf>,
+    pub sensors: Vec<String>,
+}
+
+fn string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn devnode(major: u32, minor: u32) -> Option<PathBuf> {
+    let uevent = read_to_string(format!("/sys/dev/char/{major}:{minor}/uevent")).ok()?;
+    let name = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVNAME="))?;
+    Some(Path::new("/dev").join(name))
+}
+
+pub fn query_media(node: &Path) -> Option<MediaDevice> {
+    let file = File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(node)
+        .ok()?;
+    let fd = file.as_raw_fd();
+
+    let mut info: MediaDeviceInfo = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, MEDIA_IOC_DEVICE_INFO as _, &raw mut info) } < 0 {
+        return None;
+    }
+
+    // The first call only fills in the counts
+    let mut topology = MediaV2Topology::default();
+    if unsafe { libc::ioctl(fd, MEDIA_IOC_G_TOPOLOGY as _, &raw mut topology) } < 0 {
+        return None;
+    }
+    let entity: MediaV2Entity = unsafe { std::mem::zeroed() };
+    let mut entities = vec![entity; topology.num_entities as usize];
+    let mut interfaces = vec![MediaV2Interface::default(); topology.num_interfaces as usize];
+    topology.ptr_entities = entities.as_mut_ptr() as u64;
+    topology.ptr_interfaces = interfaces.as_mut_ptr() as u64;
+    // Fails with ENOSPC if the graph grew in between
+    if unsafe { libc::ioctl(fd, MEDIA_IOC_G_TOPOLOGY as _, &raw mut topology) } < 0 {
+        return None;
+    }
+
+    let sensors = entities
+        .iter()
+        .filter(|entity| entity.function == MEDIA_ENT_F_CAM_SENSOR)
+        .map(|entity| string(&entity.name))
+        .collect();
+    let nodes = interfaces
+        .iter()
+        .filter(|interface| interface.intf_type == MEDIA_INTF_T_V4L_VIDEO)
+        .filter_map(|interface| devnode(interface.major, interface.minor))
+        .collect();
+    Some(MediaDevice {
+        driver: string(&info.driver),
+        model: string(&info.model),
+        nodes,
+        sensors,
+    })
+}
@@ -0,0 +1,361 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::camera::{Device, Process, Scope};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    // Already held the device when the applet started
+    Preexisting,
+    // Showed up after an open event
+    Opened(Instant),
+    // Only found by a /proc rescan, so when it was opened is unknown
+    Discovered,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Holder {
+    pub process: Process,
+    pub origin: Origin,
+}
+
+impl Holder {
+    // When a holder that opened too recently to be told apart from a probe starts counting
+    fn pending_until(&self, min_hold: Duration, now: Instant) -> Option<Instant> {
+        match self.origin {
+            Origin::Opened(since) if now < since + min_hold => Some(since + min_hold),
+            _ => None,
+        }
+    }
+}
+
+// Open handles of every watched device.
+// Devices without holders are never stored, so an empty tracker means no device in use.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    devices: HashMap<Device, Vec<Holder>>,
+}
+
+impl Sessions {
+    pub fn start(&mut self, scope: Scope, devices: HashMap<Device, Vec<Process>>) {
+        self.devices.retain(|device, _| !scope.covers(device.kind));
+        for (device, processes) in devices {
+            self.set(device, processes, Origin::Preexisting);
+        }
+    }
+
+    // Holders of a device after an open or close event on it
+    pub fn update(&mut self, device: Device, processes: Vec<Process>, now: Instant) {
+        self.set(device, processes, Origin::Opened(now));
+    }
+
+    pub fn reconcile(&mut self, scope: Scope, devices: HashMap<Device, Vec<Process>>) {
+        self.devices
+            .retain(|device, _| !scope.covers(device.kind) || devices.contains_key(device));
+        for (device, processes) in devices {
+            self.set(device, processes, Origin::Discovered);
+        }
+    }
+
+    pub fn reset(&mut self, device: &Device) {
+        self.devices.remove(device);
+    }
+
+    fn set(&mut self, device: Device, processes: Vec<Process>, origin: Origin) {
+        let previous = self.devices.remove(&device).unwrap_or_default();
+        let holders = processes
+            .into_iter()
+            .map(|process| {
+                // Known holders keep their origin, closes of unknown handles change nothing
+                let origin = previous
+                    .iter()
+                    .find(|holder| holder.process.pid == process.pid)
+                    .map_or(origin, |holder| holder.origin);
+                Holder { process, origin }
+            })
+            .collect::<Vec<_>>();
+        if !holders.is_empty() {
+            self.devices.insert(device, holders);
+        }
+    }
+
+    pub fn holders(&self) -> impl Iterator<Item = (&Device, &Holder)> {
+        self.devices
+            .iter()
+            .flat_map(|(device, holders)| holders.iter().map(move |holder| (device, holder)))
+    }
+
+    // Whether a real session is running, and otherwise when a pending holder would count.
+    // Probes open the device for a few milliseconds, real sessions outlive the minimum hold.
+    // Holders listed in `ignored` never count, e.g. the sound server owning the PCMs.
+    pub fn active(
+        &self,
+        filter: impl Fn(&Device) -> bool,
+        ignored: &[String],
+        min_hold: Duration,
+        now: Instant,
+    ) -> (bool, Option<Instant>) {
+        let mut pending: Option<Instant> = None;
+        for (device, holder) in self.holders() {
+            if !filter(device) || ignored.contains(&holder.process.comm) {
+                continue;
+            }
+            match holder.pending_until(min_hold, now) {
+                Some(until) => pending = Some(pending.map_or(until, |pending| pending.min(until))),
+                None => return (true, None),
+            }
+        }
+        (false, pending)
+    }
+
+    // Holders that make `active` true
+    pub fn sessions<'a>(
+        &'a self,
+        filter: impl Fn(&Device) -> bool + 'a,
+        ignored: &'a [String],
+        min_hold: Duration,
+        now: Instant,
+    ) -> impl Iterator<Item = (&'a Device, &'a Holder)> + 'a {
+        self.holders().filter(move |(device, holder)| {
+            filter(device)
+                && !ignored.contains(&holder.process.comm)
+                && holder.pending_until(min_hold, now).is_none()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::{camera::Kind, hotplug::DeviceInfo};
+
+    fn device(node: &str) -> Device {
+        Device {
+            path: PathBuf::from(node),
+            kind: Kind::Camera,
+            info: DeviceInfo::default(),
+        }
+    }
+
+    fn process(pid: u32) -> Process {
+        Process {
+            pid,
+            comm: format!("app{pid}"),
+            exe: None,
+            cmdline: vec![],
+            app_id: None,
+            uid: None,
+        }
+    }
+
+    fn processes(pids: &[u32]) -> Vec<Process> {
+        pids.iter().copied().map(process).collect()
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Update(&'static str, &'static [u32]),
+        Reconcile(&'static [(&'static str, &'static [u32])]),
+        Reset(&'static str),
+    }
+
+    const OPS: [Op; 8] = [
+        Op::Update("/dev/video0", &[]),
+        Op::Update("/dev/video0", &[1]),
+        Op::Update("/dev/video0", &[1, 2]),
+        Op::Update("/dev/video2", &[2]),
+        Op::Update("/dev/video2", &[]),
+        Op::Reset("/dev/video0"),
+        Op::Reconcile(&[("/dev/video2", &[2])]),
+        Op::Reconcile(&[]),
+    ];
+
+    fn holders(sessions: &Sessions) -> HashMap<(Device, u32), Origin> {
+        sessions
+            .holders()
+            .map(|(device, holder)| ((device.clone(), holder.process.pid), holder.origin))
+            .collect()
+    }
+
+    // Every sequence of up to `len` operations, as indices into OPS
+    fn sequences(len: usize) -> impl Iterator<Item = Vec<usize>> {
+        (0..=len).flat_map(|len| {
+            (0..OPS.len().pow(len as u32)).map(move |mut n| {
+                (0..len)
+                    .map(|_| {
+                        let op = n % OPS.len();
+                        n /= OPS.len();
+                        op
+                    })
+                    .collect()
+            })
+        })
+    }
+
+    // Checks the tracker against a plain map of who holds what, over every ordering of
+    // opens, closes, resets and rescans
+    #[test]
+    fn orderings() {
+        let start = Instant::now();
+        for sequence in sequences(5) {
+            let mut sessions = Sessions::default();
+            sessions.start(
+                Scope::All,
+                HashMap::from([(device("/dev/video0"), processes(&[1]))]),
+            );
+            let mut expected = HashMap::from([(device("/dev/video0"), vec![1])]);
+
+            for (step, &op) in sequence.iter().enumerate() {
+                let now = start + Duration::from_secs(step as u64 + 1);
+                let before = holders(&sessions);
+                match &OPS[op] {
+                    Op::Update(node, pids) => {
+                        sessions.update(device(node), processes(pids), now);
+                        expected.insert(device(node), pids.to_vec());
+                    }
+                    Op::Reconcile(devices) => {
+                        let devices = devices
+                            .iter()
+                            .map(|(node, pids)| (device(node), processes(pids)))
+                            .collect::<HashMap<_, _>>();
+                        expected = devices
+                            .iter()
+                            .map(|(device, processes)| {
+                                (device.clone(), processes.iter().map(|p| p.pid).collect())
+                            })
+                            .collect();
+                        sessions.reconcile(Scope::All, devices);
+                    }
+                    Op::Reset(node) => {
+                        sessions.reset(&device(node));
+                        expected.remove(&device(node));
+                    }
+                }
+                expected.retain(|_, pids| !pids.is_empty());
+
+                let after = holders(&sessions);
+                let mut held = after.keys().cloned().collect::<Vec<_>>();
+                let mut wanted = expected
+                    .iter()
+                    .flat_map(|(device, pids)| pids.iter().map(|&pid| (device.clone(), pid)))
+                    .collect::<Vec<_>>();
+                held.sort_by_key(|(device, pid)| (device.path.clone(), *pid));
+                wanted.sort_by_key(|(device, pid)| (device.path.clone(), *pid));
+                assert_eq!(held, wanted, "after {sequence:?}");
+
+                for (key, origin) in &after {
+                    // Holders that stayed keep their origin, new ones are stamped with this step
+                    match before.get(key) {
+                        Some(previous) => assert_eq!(origin, previous, "after {sequence:?}"),
+                        None => assert!(
+                            matches!(
+                                (&OPS[op], origin),
+                                (Op::Update(..), Origin::Opened(since)) if *since == now
+                            ) || matches!(
+                                (&OPS[op], origin),
+                                (Op::Reconcile(_), Origin::Discovered)
+                            ),
+                            "after {sequence:?}"
+                        ),
+                    }
+                }
+
+                // Without a minimum hold, any holder counts, unless it's ignored
+                let (active, pending) = sessions.active(|_| true, &[], Duration::ZERO, now);
+                assert_eq!(active, !expected.is_empty(), "after {sequence:?}");
+                assert_eq!(pending, None);
+                let ignored = (1..=2).map(|pid| process(pid).comm).collect::<Vec<_>>();
+                let (active, _) = sessions.active(|_| true, &ignored, Duration::ZERO, now);
+                assert!(!active);
+            }
+        }
+    }
+
+    #[test]
+    fn probes_are_pending_until_the_minimum_hold() {
+        let now = Instant::now();
+        let min_hold = Duration::from_secs(2);
+        let mut sessions = Sessions::default();
+        sessions.update(device("/dev/video0"), processes(&[1]), now);
+
+        let (active, pending) = sessions.active(|_| true, &[], min_hold, now);
+        assert!(!active);
+        assert_eq!(pending, Some(now + min_hold));
+        assert_eq!(sessions.sessions(|_| true, &[], min_hold, now).count(), 0);
+
+        let (active, pending) = sessions.active(|_| true, &[], min_hold, now + min_hold);
+        assert!(active);
+        assert_eq!(pending, None);
+        let later = now + min_hold;
+        assert_eq!(sessions.sessions(|_| true, &[], min_hold, later).count(), 1);
+
+        // A probe that closed before the minimum hold never counts
+        sessions.update(device("/dev/video0"), vec![], now + Duration::from_secs(1));
+        let (active, pending) = sessions.active(|_| true, &[], min_hold, now + min_hold);
+        assert!(!active);
+        assert_eq!(pending, None);
+    }
+
+    #[test]
+    fn preexisting_and_discovered_holders_count_immediately() {
+        let now = Instant::now();
+        let min_hold = Duration::from_secs(2);
+        let mut sessions = Sessions::default();
+        sessions.start(
+            Scope::All,
+            HashMap::from([(device("/dev/video0"), processes(&[1]))]),
+        );
+        assert_eq!(sessions.active(|_| true, &[], min_hold, now), (true, None));
+
+        sessions.reconcile(
+            Scope::All,
+            HashMap::from([(device("/dev/video2"), processes(&[2]))]),
+        );
+        assert_eq!(sessions.active(|_| true, &[], min_hold, now), (true, None));
+        let filter = |device: &Device| device.path == Path::new("/dev/video0");
+        assert_eq!(sessions.active(filter, &[], min_hold, now), (false, None));
+    }
+
+    #[test]
+    fn rescans_only_replace_devices_in_their_scope() {
+        let class = Device {
+            kind: Kind::Class(0),
+            ..device("/dev/ttyUSB0")
+        };
+        let mut sessions = Sessions::default();
+        sessions.start(
+            Scope::Classes,
+            HashMap::from([(class.clone(), processes(&[1]))]),
+        );
+        sessions.start(
+            Scope::Builtin,
+            HashMap::from([(device("/dev/video0"), processes(&[2]))]),
+        );
+        let held = |sessions: &Sessions| {
+            let mut held = sessions
+                .holders()
+                .map(|(device, holder)| (device.path.clone(), holder.process.pid))
+                .collect::<Vec<_>>();
+            held.sort();
+            held
+        };
+        assert_eq!(
+            held(&sessions),
+            [
+                (PathBuf::from("/dev/ttyUSB0"), 1),
+                (PathBuf::from("/dev/video0"), 2)
+            ]
+        );
+
+        sessions.reconcile(Scope::Builtin, HashMap::new());
+        assert_eq!(held(&sessions), [(PathBuf::from("/dev/ttyUSB0"), 1)]);
+        sessions.reconcile(Scope::Classes, HashMap::new());
+        assert!(held(&sessions).is_empty());
+    }
+}
@@ -0,0 +1,788 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    mem,
+    path::Path,
+    rc::Rc,
+    time::Duration,
+};
+
+use cosmic::iced::futures::channel::mpsc::Sender;
+use pipewire::{
+    context::ContextRc,
+    core::PW_ID_CORE,
+    main_loop::{MainLoopRc, MainLoopWeak},
+    node::{Node, NodeChangeMask, NodeListener, NodeState},
+    spa::utils::dict::DictRef,
+    types::ObjectType,
+};
+
+use crate::{
+    applet::Message,
+    camera::{Kind, is_ir_camera, query_capabilities, sandboxed},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppInfo {
+    pub name: Option<String>,
+    pub pid: Option<u32>,
+    pub binary: Option<String>,
+    pub icon_name: Option<String>,
+    pub media_name: Option<String>,
+    pub flatpak_id: Option<String>,
+}
+
+impl AppInfo {
+    pub fn from_props(props: &DictRef) -> Self {
+        let get = |key: &str| {
+            props
+                .get(key)
+                .filter(|value| !value.is_empty())
+                .map(str::to_owned)
+        };
+        AppInfo {
+            name: get("application.name"),
+            pid: get("application.process.id").and_then(|pid| pid.parse().ok()),
+            binary: get("application.process.binary"),
+            icon_name: get("application.icon-name"),
+            media_name: get("media.name"),
+            // Set by the flatpak access module for sandboxed clients
+            flatpak_id: get("pipewire.access.portal.app_id"),
+        }
+    }
+
+    pub fn matches(&self, pattern: &str) -> bool {
+        [&self.name, &self.binary, &self.flatpak_id]
+            .into_iter()
+            .flatten()
+            .any(|value| value.eq_ignore_ascii_case(pattern))
+    }
+
+    pub fn display_name(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.binary.as_deref())
+            .or(self.flatpak_id.as_deref())
+            .or(self.media_name.as_deref())
+            .unwrap_or("Unknown")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamState {
+    // Paused, suspended or errored streams still hold on to the device
+    #[default]
+    Held,
+    Running,
+}
+
+impl From<NodeState<'_>> for StreamState {
+    fn from(state: NodeState<'_>) -> Self {
+        match state {
+            NodeState::Running => StreamState::Running,
+            NodeState::Idle | NodeState::Suspended | NodeState::Creating | NodeState::Error(_) => {
+                StreamState::Held
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub app: AppInfo,
+    pub state: StreamState,
+    // Description of the source node the stream is reading from
+    pub device: Option<String>,
+    // Volume meters, loopbacks and filter internals that don't record anything
+    pub harmless: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Usage {
+    #[default]
+    None,
+    Held,
+    Active,
+}
+
+impl Usage {
+    pub fn of<'a>(streams: impl IntoIterator<Item = &'a Stream>) -> Self {
+        streams
+            .into_iter()
+            .map(|stream| match stream.state {
+                StreamState::Running => Usage::Active,
+                StreamState::Held => Usage::Held,
+            })
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Category {
+    Microphone,
+    DesktopAudio,
+    ScreenShare,
+    Camera,
+    VirtualCamera,
+    Biometric,
+}
+
+impl Category {
+    fn message(self, id: u32, stream: Stream) -> Message {
+        match self {
+            Category::Microphone => Message::MicrophoneAdd(id, stream),
+            Category::DesktopAudio => Message::DesktopAudioAdd(id, stream),
+            Category::ScreenShare => Message::ScreenShareAdd(id, stream),
+            Category::Camera => Message::CameraStreamAdd(id, stream),
+            Category::VirtualCamera => Message::VirtualCameraStreamAdd(id, stream),
+            Category::Biometric => Message::BiometricStreamAdd(id, stream),
+        }
+    }
+}
+
+// Filter chains may be stacked (e.g. noise suppression on top of echo-cancel)
+const MAX_FILTER_DEPTH: usize = 8;
+
+#[derive(Debug, Default)]
+struct NodeProps {
+    media_class: Option<String>,
+    device_api: Option<String>,
+    description: Option<String>,
+    link_group: Option<String>,
+    capture_sink: bool,
+    passive: bool,
+    monitor: bool,
+    v4l2_path: Option<String>,
+    // What the v4l2 device behind a source is, if it could be queried
+    v4l2_kind: Option<Kind>,
+}
+
+impl NodeProps {
+    // Cameras are exposed by the v4l2 and libcamera monitors
+    fn camera_category(&self) -> Option<Category> {
+        let camera = self.media_class.as_deref() == Some("Video/Source")
+            && matches!(self.device_api.as_deref(), Some("v4l2" | "libcamera"));
+        camera.then_some(match self.v4l2_kind {
+            Some(Kind::VirtualCamera) => Category::VirtualCamera,
+            Some(Kind::Biometric) => Category::Biometric,
+            _ => Category::Camera,
+        })
+    }
+
+    fn is_harmless(&self) -> bool {
+        // Loopbacks and filter chains link their internal streams in a group,
+        // the application reading from the filter is the one reported
+        self.passive || self.monitor || self.link_group.is_some()
+    }
+}
+
+#[derive(Debug)]
+struct Capture {
+    media_class: String,
+    stream: Stream,
+    category: Option<Category>,
+    dirty: bool,
+}
+
+#[derive(Debug, Default)]
+struct Graph {
+    nodes: HashMap<u32, NodeProps>,
+    device_apis: HashMap<u32, String>,
+    // link id -> (output node, input node)
+    links: HashMap<u32, (u32, u32)>,
+    captures: HashMap<u32, Capture>,
+    // Inside a sandbox /proc can't tell who holds a camera, but its source still starts running
+    watch_sources: bool,
+    // Camera sources that are currently running
+    running: HashSet<u32>,
+    // Camera source -> category it was reported under while standing in for its application
+    running_sources: HashMap<u32, Category>,
+    // Cameras, by name or node, configured to be reported as biometric
+    ir_cameras: Vec<String>,
+    // v4l2 node -> what it was queried to be, kept across reconnects
+    v4l2_kinds: HashMap<String, Option<Kind>>,
+}
+
+impl Graph {
+    fn add_node(&mut self, id: u32, props: &DictRef) {
+        let get = |key: &str| props.get(key).map(str::to_owned);
+        let device_api = get("device.api")
+            .or_else(|| {
+                let device = props.get("device.id")?.parse().ok()?;
+                self.device_apis.get(&device).cloned()
+            })
+            .or_else(|| {
+                // Nodes created by the spa monitors are named after their api
+                let name = props.get("node.name")?;
+                ["v4l2", "libcamera"]
+                    .into_iter()
+                    .find(|api| name.starts_with(&format!("{api}_")))
+                    .map(str::to_owned)
+            });
+        let v4l2_path = get("api.v4l2.path");
+        let v4l2_kind = v4l2_path.as_deref().and_then(|path| {
+            // Opening a node shows up as the applet holding the camera, so it's only done once
+            *self.v4l2_kinds.entry(path.to_owned()).or_insert_with(|| {
+                // The driver is only sometimes copied from the device
+                if props.get("api.v4l2.cap.driver") == Some("v4l2 loopback") {
+                    Some(Kind::VirtualCamera)
+                } else {
+                    query_capabilities(Path::new(path)).and_then(|caps| caps.kind())
+                }
+            })
+        });
+        let v4l2_kind = match v4l2_kind {
+            // Sources are reported as cameras unless they're known to be something else
+            None | Some(Kind::Camera)
+                if v4l2_path.as_deref().is_some_and(|path| {
+                    let card = props.get("api.v4l2.cap.card");
+                    is_ir_camera(&self.ir_cameras, Path::new(path), card)
+                }) =>
+            {
+                Some(Kind::Biometric)
+            }
+            kind => kind,
+        };
+        self.nodes.insert(
+            id,
+            NodeProps {
+                media_class: get("media.class"),
+                device_api,
+                description: get("node.description")
+                    .or_else(|| get("node.nick"))
+                    .or_else(|| get("node.name")),
+                link_group: get("node.link-group"),
+                capture_sink: props.get("stream.capture.sink") == Some("true"),
+                // Peak meters don't wake up the source they read from
+                passive: props
+                    .get("node.passive")
+                    .is_some_and(|passive| passive != "false"),
+                monitor: props.get("stream.monitor") == Some("true")
+                    || props.get("media.name") == Some("Peak detect"),
+                v4l2_path,
+                v4l2_kind,
+            },
+        );
+    }
+
+    fn sources(&self, id: u32) -> impl Iterator<Item = u32> + '_ {
+        self.links
+            .values()
+            .filter(move |&&(_, input)| input == id)
+            .map(|&(output, _)| output)
+    }
+
+    // Resolves the node a capture stream is ultimately reading from
+    fn origin(&self, id: u32) -> Option<u32> {
+        let mut node = self.sources(id).next()?;
+        // Filters expose a virtual source backed by a capture stream in the same link group
+        for _ in 0..MAX_FILTER_DEPTH {
+            let Some(group) = self
+                .nodes
+                .get(&node)
+                .and_then(|props| props.link_group.as_deref())
+            else {
+                break;
+            };
+            let Some(upstream) = self
+                .nodes
+                .iter()
+                .filter(|&(&other, props)| {
+                    other != node
+                        && props.link_group.as_deref() == Some(group)
+                        && props
+                            .media_class
+                            .as_deref()
+                            .is_some_and(|class| class.starts_with("Stream/Input"))
+                })
+                .find_map(|(&other, _)| self.sources(other).next())
+            else {
+                break;
+            };
+            node = upstream;
+        }
+        Some(node)
+    }
+
+    fn classify(&self, id: u32, capture: &Capture, origin: Option<u32>) -> Option<Category> {
+        let props = |id| self.nodes.get(&id);
+        match capture.media_class.as_str() {
+            // Recording desktop audio is done through the monitor ports of a sink
+            "Stream/Input/Audio"
+                if props(id).is_some_and(|node| node.capture_sink)
+                    || origin
+                        .and_then(props)
+                        .is_some_and(|node| node.media_class.as_deref() == Some("Audio/Sink")) =>
+            {
+                Some(Category::DesktopAudio)
+            }
+            "Stream/Input/Audio" => Some(Category::Microphone),
+            // Unlinked video streams aren't capturing anything yet
+            "Stream/Input/Video" => origin.map(|source| {
+                // Screen captures/recordings in wayland are usually done through pipewire
+                props(source)
+                    .and_then(NodeProps::camera_category)
+                    .unwrap_or(Category::ScreenShare)
+            }),
+            _ => None,
+        }
+    }
+
+    fn refresh(&mut self, output: &Sender<Message>) {
+        let resolved = self
+            .captures
+            .iter()
+            .map(|(&id, capture)| {
+                let origin = self.origin(id);
+                let device = origin
+                    .and_then(|origin| self.nodes.get(&origin))
+                    .and_then(|props| props.description.clone());
+                let harmless = self.nodes.get(&id).is_some_and(NodeProps::is_harmless);
+                (id, self.classify(id, capture, origin), device, harmless)
+            })
+            .collect::<Vec<_>>();
+        for (id, category, device, harmless) in resolved {
+            let capture = self.captures.get_mut(&id).expect("Capture was just listed");
+            if capture.stream.harmless != harmless {
+                capture.stream.harmless = harmless;
+                capture.dirty = true;
+            }
+            if capture.stream.device != device {
+                capture.stream.device = device;
+                capture.dirty = true;
+            }
+            if capture.category != category {
+                if capture.category.is_some() {
+                    send(output, Message::PipeWireNodeRemove(id));
+                }
+                capture.category = category;
+                capture.dirty = true;
+            }
+            if capture.dirty
+                && let Some(category) = capture.category
+            {
+                send(output, category.message(id, capture.stream.clone()));
+            }
+            capture.dirty = false;
+        }
+        self.refresh_sources(output);
+    }
+
+    fn update_source(&mut self, id: u32, running: bool) {
+        if !self.watch_sources {
+            return;
+        }
+        if running {
+            self.running.insert(id);
+        } else {
+            self.running.remove(&id);
+        }
+    }
+
+    fn refresh_sources(&mut self, output: &Sender<Message>) {
+        let ids = self.running.iter().chain(self.running_sources.keys());
+        let changed = ids
+            .map(|&id| {
+                // Capture streams that can be seen are reported on their own
+                let captured = self
+                    .links
+                    .values()
+                    .filter(|&&(source, _)| source == id)
+                    .filter_map(|(_, input)| self.captures.get(input))
+                    .any(|capture| capture.stream.state == StreamState::Running);
+                let category = self
+                    .nodes
+                    .get(&id)
+                    .and_then(NodeProps::camera_category)
+                    .filter(|_| self.running.contains(&id) && !captured);
+                (id, category)
+            })
+            .filter(|(id, category)| self.running_sources.get(id) != category.as_ref())
+            .collect::<HashMap<_, _>>();
+        for (id, category) in changed {
+            if self.running_sources.remove(&id).is_some() {
+                send(output, Message::PipeWireNodeRemove(id));
+            }
+            let Some(category) = category else {
+                continue;
+            };
+            let node = &self.nodes[&id];
+            // The application behind it can't be known, so the source stands in for it
+            let stream = Stream {
+                app: AppInfo {
+                    name: node.description.clone(),
+                    ..Default::default()
+                },
+                state: StreamState::Running,
+                device: node.description.clone(),
+                harmless: false,
+            };
+            send(output, category.message(id, stream));
+            self.running_sources.insert(id, category);
+        }
+    }
+
+    fn update(&mut self, id: u32, f: impl FnOnce(&mut Stream)) {
+        if let Some(capture) = self.captures.get_mut(&id) {
+            f(&mut capture.stream);
+            capture.dirty = true;
+        }
+    }
+
+    fn remove(&mut self, id: u32, output: &Sender<Message>) {
+        // A node coming back under the same path may be another device
+        if let Some(NodeProps {
+            v4l2_path: Some(path),
+            ..
+        }) = self.nodes.remove(&id)
+        {
+            self.v4l2_kinds.remove(&path);
+        }
+        self.device_apis.remove(&id);
+        self.links.remove(&id);
+        self.running.remove(&id);
+        if let Some(capture) = self.captures.remove(&id)
+            && capture.category.is_some()
+        {
+            send(output, Message::PipeWireNodeRemove(id));
+        }
+        if self.running_sources.remove(&id).is_some() {
+            send(output, Message::PipeWireNodeRemove(id));
+        }
+        self.refresh(output);
+    }
+}
+
+fn send(output: &Sender<Message>, message: Message) -> bool {
+    let mut output = output.clone();
+    loop {
+        match output.try_send(message.clone()) {
+            Ok(()) => return true,
+            Err(err) if err.is_disconnected() => return false,
+            Err(_) => eprintln!("Failed to send PipeWire event"),
+        }
+    }
+}
+
+// Nobody is listening anymore once the applet is gone
+fn quit_if_closed(main_loop: &MainLoopWeak, output: &Sender<Message>) {
+    if output.is_closed()
+        && let Some(main_loop) = main_loop.upgrade()
+    {
+        main_loop.quit();
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub fn listen(output: Sender<Message>, ir_cameras: Vec<String>) {
+    pipewire::init();
+    let mut backoff = MIN_BACKOFF;
+    let mut v4l2_kinds = HashMap::new();
+    loop {
+        match connect(&output, &ir_cameras, &mut v4l2_kinds) {
+            // The session ran until the daemon went away, so retry right away
+            Ok(()) => backoff = MIN_BACKOFF,
+            Err(err) => eprintln!("Failed to connect to PipeWire: {err}"),
+        }
+        // Nodes from the previous connection will never get a global_remove
+        if !send(&output, Message::PipeWireReset) {
+            return;
+        }
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn connect(
+    output: &Sender<Message>,
+    ir_cameras: &[String],
+    v4l2_kinds: &mut HashMap<String, Option<Kind>>,
+) -> Result<(), pipewire::Error> {
+    let main_loop = MainLoopRc::new(None)?;
+    let context = ContextRc::new(&main_loop, None)?;
+    let core = context.connect_rc(None)?;
+    let registry = core.get_registry_rc()?;
+
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let main_loop = main_loop.downgrade();
+            move |id, _seq, res, message| {
+                // Errors on the core object mean the connection itself is gone
+                if id == PW_ID_CORE {
+                    eprintln!("PipeWire connection lost ({res}): {message}");
+                    if let Some(main_loop) = main_loop.upgrade() {
+                        main_loop.quit();
+                    }
+                }
+            }
+        })
+        .register();
+
+    let graph = Rc::new(RefCell::new(Graph {
+        watch_sources: sandboxed(),
+        ir_cameras: ir_cameras.to_vec(),
+        v4l2_kinds: mem::take(v4l2_kinds),
+        ..Default::default()
+    }));
+    // Bound proxies must be kept alive for their info events to keep coming
+    let nodes: Rc<RefCell<HashMap<u32, (Node, NodeListener)>>> = Rc::default();
+    let weak_loop = Rc::new(main_loop.downgrade());
+
+    let _listener = registry
+        .add_listener_local()
+        .global({
+            let registry = registry.downgrade();
+            let graph = graph.clone();
+            let nodes = nodes.clone();
+            let output = output.clone();
+            let weak_loop = weak_loop.clone();
+            move |global| {
+                let Some(props) = global.props else {
+                    return;
+                };
+                match global.type_ {
+                    ObjectType::Device => {
+                        if let Some(api) = props.get("device.api") {
+                            graph
+                                .borrow_mut()
+                                .device_apis
+                                .insert(global.id, api.to_owned());
+                        }
+                        return;
+                    }
+                    ObjectType::Link => {
+                        let node = |key| props.get(key).and_then(|id| id.parse::<u32>().ok());
+                        if let (Some(output_node), Some(input_node)) =
+                            (node("link.output.node"), node("link.input.node"))
+                        {
+                            let mut graph = graph.borrow_mut();
+                            graph.links.insert(global.id, (output_node, input_node));
+                            graph.refresh(&output);
+                        }
+                        quit_if_closed(&weak_loop, &output);
+                        return;
+                    }
+                    ObjectType::Node => graph.borrow_mut().add_node(global.id, props),
+                    _ => return,
+                }
+
+                match props.get("media.class") {
+                    Some(media_class @ ("Stream/Input/Video" | "Stream/Input/Audio")) => {
+                        let mut graph = graph.borrow_mut();
+                        graph.captures.insert(
+                            global.id,
+                            Capture {
+                                media_class: media_class.to_owned(),
+                                stream: Stream {
+                                    app: AppInfo::from_props(props),
+                                    ..Default::default()
+                                },
+                                category: None,
+                                dirty: false,
+                            },
+                        );
+                        graph.refresh(&output);
+                        quit_if_closed(&weak_loop, &output);
+                    }
+                    // Sources are bound for the props that global objects don't carry
+                    Some(media_class)
+                        if media_class.starts_with("Audio/Source")
+                            || media_class.starts_with("Video/Source") => {}
+                    _ => return,
+                }
+
+                let Some(registry) = registry.upgrade() else {
+                    return;
+                };
+                let node: Node = match registry.bind(global) {
+                    Ok(node) => node,
+                    Err(err) => {
+                        eprintln!("Failed to bind PipeWire node {}: {err}", global.id);
+                        return;
+                    }
+                };
+                let id = global.id;
+                let graph = graph.clone();
+                let output = output.clone();
+                let weak_loop = weak_loop.clone();
+                let listener = node
+                    .add_listener_local()
+                    .info(move |info| {
+                        let mask = info.change_mask();
+                        let mut graph = graph.borrow_mut();
+                        // Node info carries the full props, global props are filtered
+                        let props = info
+                            .props()
+                            .filter(|_| mask.contains(NodeChangeMask::PROPS));
+                        if let Some(props) = props {
+                            graph.add_node(id, props);
+                        }
+                        graph.update(id, |stream| {
+                            if let Some(props) = props {
+                                stream.app = AppInfo::from_props(props);
+                            }
+                            if mask.contains(NodeChangeMask::STATE) {
+                                stream.state = info.state().into();
+                            }
+                        });
+                        if mask.contains(NodeChangeMask::STATE) {
+                            let running = matches!(info.state(), NodeState::Running);
+                            graph.update_source(id, running);
+                        }
+                        graph.refresh(&output);
+                        quit_if_closed(&weak_loop, &output);
+                    })
+                    .register();
+                nodes.borrow_mut().insert(id, (node, listener));
+            }
+        })
+        .global_remove({
+            let nodes = nodes.clone();
+            let output = output.clone();
+            move |id| {
+                nodes.borrow_mut().remove(&id);
+                graph.borrow_mut().remove(id, &output);
+                quit_if_closed(&weak_loop, &output);
+            }
+        })
+        .register();
+
+    main_loop.run();
+
+    *v4l2_kinds = mem::take(&mut graph.borrow_mut().v4l2_kinds);
+    // Proxies have to be dropped before the core they were bound on
+    nodes.borrow_mut().clear();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::PathBuf,
+        process::{Child, Command},
+        time::Instant,
+    };
+
+    use cosmic::iced::futures::{
+        FutureExt, StreamExt,
+        channel::mpsc::{Receiver, channel},
+    };
+
+    use super::*;
+
+    // Killed when dropped, so that a failing test doesn't leave it running
+    struct Process(Child);
+
+    impl Process {
+        fn spawn(program: &str, args: &[&str]) -> Self {
+            let child = Command::new(program)
+                .args(args)
+                .spawn()
+                .unwrap_or_else(|err| panic!("Failed to start {program}: {err}"));
+            Process(child)
+        }
+    }
+
+    impl Drop for Process {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn next(receiver: &mut Receiver<Message>, matches: impl Fn(&Message) -> bool) -> Message {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Instant::now() < deadline {
+            match receiver.next().now_or_never() {
+                Some(Some(message)) if matches(&message) => return message,
+                Some(Some(_)) => {}
+                Some(None) => panic!("PipeWire watcher stopped"),
+                None => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        panic!("Timed out waiting for the PipeWire watcher");
+    }
+
+    fn recording(pid: u32) -> impl Fn(&Message) -> bool {
+        move |message| {
+            matches!(
+                message,
+                Message::MicrophoneAdd(_, stream) if stream.app.pid == Some(pid)
+            )
+        }
+    }
+
+    // Clients started before the socket exists would fail right away
+    fn start_daemon(runtime: &Path) -> Process {
+        let socket = runtime.join("pipewire-0");
+        let _ = fs::remove_file(&socket);
+        let daemon = Process::spawn("pipewire", &[]);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !socket.exists() {
+            assert!(Instant::now() < deadline, "PipeWire didn't start");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        daemon
+    }
+
+    // Set on the copy of the test binary that runs against its own daemon
+    const ISOLATED: &str = "PRIVACY_INDICATOR_ISOLATED_TEST";
+
+    #[test]
+    #[ignore = "starts a local PipeWire daemon, needs pipewire and pw-record"]
+    fn reconnects_after_a_daemon_restart() {
+        // PipeWire finds its daemon through the environment, which can't be changed while
+        // other tests run, so the test runs again in a process of its own
+        if env::var_os(ISOLATED).is_none() {
+            let runtime =
+                env::temp_dir().join(format!("privacy-indicator-pw-{}", std::process::id()));
+            fs::create_dir_all(&runtime).unwrap();
+            let status = Command::new(env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "stream::tests::reconnects_after_a_daemon_restart",
+                ])
+                .args(["--ignored", "--nocapture"])
+                .env(ISOLATED, "1")
+                .env("PIPEWIRE_RUNTIME_DIR", &runtime)
+                .status()
+                .unwrap();
+            let _ = fs::remove_dir_all(&runtime);
+            assert!(status.success());
+            return;
+        }
+        let runtime = PathBuf::from(env::var_os("PIPEWIRE_RUNTIME_DIR").unwrap());
+        let capture = runtime.join("capture.wav");
+        let capture = capture.to_str().unwrap();
+
+        let (output, mut receiver) = channel(100);
+        let watcher = std::thread::spawn(move || listen(output, Vec::new()));
+
+        let daemon = start_daemon(&runtime);
+        let recorder = Process::spawn("pw-record", &[capture]);
+        next(&mut receiver, recording(recorder.0.id()));
+
+        drop(daemon);
+        next(&mut receiver, |message| {
+            matches!(message, Message::PipeWireReset)
+        });
+        drop(recorder);
+
+        // Everything is enumerated again on the new connection
+        let _daemon = start_daemon(&runtime);
+        let recorder = Process::spawn("pw-record", &[capture]);
+        next(&mut receiver, recording(recorder.0.id()));
+
+        // The watcher stops once nobody listens anymore
+        drop(receiver);
+        drop(recorder);
+        watcher.join().unwrap();
+    }
+}